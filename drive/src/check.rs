@@ -0,0 +1,150 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use futures::{stream, StreamExt};
+use google_drive3::api::File;
+use tokio::time::sleep;
+
+use crate::{
+    download::{get_api_error, is_rate_limit_error, live_metadata, DRIVE_READONLY_SCOPE},
+    interface::{LinkCheck, LinkStatus, LinkVisibility},
+    link::Link,
+    DriveManager,
+};
+
+// Links checked at the same time, keeps bursts well below the per user quota of Drive
+static MAX_CONCURRENT_CHECKS: usize = 8;
+static MAX_CHECK_ATTEMPTS: u32 = 5;
+static RATE_LIMIT_BACKOFF_MS: u64 = 500;
+
+static CHECK_FIELDS: &str =
+    "id, name, mimeType, size, permissionIds, shortcutDetails(targetId, targetMimeType)";
+
+// Link checks must reflect the current sharing state, so the metadata cache is skipped
+// Retries requests refused because of rate limits with an exponential backoff
+async fn checked_metadata(drive: Arc<DriveManager>, file_id: &str) -> Result<File> {
+    let mut attempt = 1;
+    loop {
+        match live_metadata(drive.clone(), file_id, Some(CHECK_FIELDS)).await {
+            Err(e) if is_rate_limit_error(&e) && attempt < MAX_CHECK_ATTEMPTS => {
+                sleep(Duration::from_millis(
+                    RATE_LIMIT_BACKOFF_MS * 2_u64.pow(attempt - 1),
+                ))
+                .await;
+                attempt += 1;
+            }
+            file_metadata => return file_metadata,
+        }
+    }
+}
+
+// Drive gives the permissions anyone with the link gets these fixed IDs
+fn is_public(permission_ids: &[String]) -> bool {
+    permission_ids
+        .iter()
+        .any(|permission_id| permission_id == "anyoneWithLink" || permission_id == "anyone")
+}
+
+// None when the permissions cannot be listed with the access the server has
+async fn list_permission_ids(drive: Arc<DriveManager>, file_id: &str) -> Option<Vec<String>> {
+    let mut permission_ids = Vec::new();
+    let mut page_token: Option<String> = None;
+    loop {
+        let mut request = drive
+            .hub
+            .permissions()
+            .list(file_id)
+            .supports_all_drives(true)
+            .add_scope(DRIVE_READONLY_SCOPE)
+            .param("fields", "permissions(id), nextPageToken");
+        if let Some(page_token) = page_token.as_ref() {
+            request = request.page_token(page_token);
+        }
+        let (_, permission_list) = request.doit().await.ok()?;
+
+        permission_ids.extend(
+            permission_list
+                .permissions
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|permission| permission.id),
+        );
+        match permission_list.next_page_token {
+            Some(next_page_token) => page_token = Some(next_page_token),
+            None => return Some(permission_ids),
+        }
+    }
+}
+
+// permissionIds only comes back for callers allowed to see the permissions of the file
+// A file merely shared with the server as a reader cannot be told apart from a public one
+async fn get_visibility(drive: Arc<DriveManager>, file_metadata: &File) -> Option<LinkVisibility> {
+    let permission_ids = match file_metadata.permission_ids.clone() {
+        Some(permission_ids) => permission_ids,
+        None => list_permission_ids(drive, file_metadata.id.as_deref()?).await?,
+    };
+    Some(match is_public(&permission_ids) {
+        true => LinkVisibility::Public,
+        false => LinkVisibility::Restricted,
+    })
+}
+
+async fn check_link(drive: Arc<DriveManager>, url: String) -> LinkCheck {
+    let link = Link::new(url.clone());
+    if !link.is_drive_link() {
+        return LinkCheck::new(url, LinkStatus::InvalidLink);
+    }
+
+    let mut file_metadata = checked_metadata(drive.clone(), &link.id).await;
+    // Shortcuts are checked by what they point at
+    if let std::result::Result::Ok(File {
+        shortcut_details: Some(shortcut_details),
+        ..
+    }) = file_metadata.as_ref()
+    {
+        if let Some(target_id) = shortcut_details.target_id.clone() {
+            file_metadata = checked_metadata(drive.clone(), target_id.as_str()).await;
+        }
+    }
+
+    let file_metadata = match file_metadata {
+        std::result::Result::Ok(file_metadata) => file_metadata,
+        Err(e) => {
+            let status = match get_api_error(&e) {
+                _ if is_rate_limit_error(&e) => LinkStatus::Error,
+                Some((404, _)) => LinkStatus::NotFound,
+                Some((403, _)) => LinkStatus::PermissionDenied,
+                _ => LinkStatus::Error,
+            };
+            return LinkCheck {
+                file_id: Some(link.id),
+                error: Some(e.to_string().trim().to_string()),
+                ..LinkCheck::new(url, status)
+            };
+        }
+    };
+
+    let mime_type = file_metadata.mime_type.clone().unwrap_or_default();
+    let visibility = get_visibility(drive.clone(), &file_metadata).await;
+
+    LinkCheck {
+        file_id: file_metadata.id,
+        name: file_metadata.name,
+        is_folder: mime_type == "application/vnd.google-apps.folder",
+        is_workspace_file: mime_type.starts_with("application/vnd.google-apps")
+            && mime_type != "application/vnd.google-apps.folder",
+        mime_type: Some(mime_type),
+        size: file_metadata.size,
+        visibility,
+        ..LinkCheck::new(url, LinkStatus::Accessible)
+    }
+}
+
+// Checks every link through its metadata only, the results keep the order of the links
+pub async fn check_links(drive: Arc<DriveManager>, links: Vec<String>) -> Vec<LinkCheck> {
+    stream::iter(links)
+        .map(|link| check_link(drive.clone(), link))
+        .buffered(MAX_CONCURRENT_CHECKS)
+        .collect()
+        .await
+}
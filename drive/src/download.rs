@@ -1,77 +1,373 @@
-use std::sync::{Arc, Mutex};
+use std::{future::Future, sync::Arc};
 
 use ::fs::FileManager;
-use anyhow::{Ok, Result};
+use anyhow::{anyhow, bail, Ok, Result};
 use async_recursion::async_recursion;
-use fs::cache::RedisRequest;
+use fs::{
+    cache::RedisRequest,
+    interface::{DownloadError, ExportFormat},
+    TMP_FILES_UNCOMPRESSED_BASE_PATH,
+};
 use futures::future::join_all;
-use google_drive3::{api::File, hyper::body::HttpBody};
+use google_drive3::{
+    api::File,
+    hyper::{
+        body::HttpBody,
+        header::{AUTHORIZATION, LOCATION},
+        Body, Request,
+    },
+};
+use serde_json::Value;
+use url::Url;
+
+use tokio::{spawn, sync::OwnedMutexGuard};
+
+use crate::{
+    interface::{DownloadFilters, DownloadLink, DownloadOptions, DownloadSession, ShortcutPolicy},
+    link::Link,
+    list::get_file_list,
+    revisions::{apply_revision, revision_metadata},
+    DriveManager,
+};
+
+pub static DRIVE_READONLY_SCOPE: &str = "https://www.googleapis.com/auth/drive.readonly";
+// Metadata and listings change under the downloads, they are only reused for a short while
+pub static DRIVE_CALL_CACHE_TTL_SECS: u64 = 60;
+static MAX_EXPORT_LINK_REDIRECTS: usize = 5;
+static MAX_DOWNLOAD_ATTEMPTS: usize = 3;
+
+// files.export refuses Workspace files whose export is larger than 10 MB
+fn is_export_size_limit_error(error: &google_drive3::Error) -> bool {
+    match error {
+        google_drive3::Error::BadRequest(error_value) => {
+            error_value.to_string().contains("exportSizeLimitExceeded")
+        }
+        _ => false,
+    }
+}
+
+// Fetches the export through the exportLinks of the file which are not bound by the export size limit
+async fn download_export_link(
+    drive: Arc<DriveManager>,
+    file_id: &str,
+    mime_type: &str,
+) -> Result<Body> {
+    let file_metadata = metadata(drive.clone(), file_id, Some("exportLinks")).await?;
+    let link = file_metadata
+        .export_links
+        .unwrap_or_default()
+        .get(mime_type)
+        .cloned()
+        .ok_or_else(|| anyhow!("No export link available for {}", mime_type))?;
+    fetch_export_link(drive, link).await
+}
+
+// Revisions of Workspace files can only be exported through their own export links
+async fn download_revision_export_link(
+    drive: Arc<DriveManager>,
+    file_id: &str,
+    revision_id: &str,
+    mime_type: &str,
+) -> Result<Body> {
+    let revision = revision_metadata(drive.clone(), file_id, revision_id).await?;
+    let link = revision
+        .export_links
+        .unwrap_or_default()
+        .get(mime_type)
+        .cloned()
+        .ok_or_else(|| anyhow!("No export link available for {} in the revision", mime_type))?;
+    fetch_export_link(drive, link).await
+}
+
+// Credentials are only ever sent to Google, never to where a redirect happens to point
+fn is_google_link(link: &str) -> bool {
+    let Some(host) = Url::parse(link)
+        .ok()
+        .filter(|url| url.scheme() == "https")
+        .and_then(|url| url.host_str().map(|host| host.to_lowercase()))
+    else {
+        return false;
+    };
+    ["google.com", "googleapis.com", "googleusercontent.com"]
+        .iter()
+        .any(|domain| host == *domain || host.ends_with(format!(".{}", domain).as_str()))
+}
+
+async fn fetch_export_link(drive: Arc<DriveManager>, mut link: String) -> Result<Body> {
+    let token = drive
+        .hub
+        .auth
+        .get_token(&[DRIVE_READONLY_SCOPE])
+        .await
+        .map_err(|e| anyhow!(e.to_string()))?;
+
+    for _ in 0..=MAX_EXPORT_LINK_REDIRECTS {
+        let mut request = Request::get(link.as_str());
+        if let Some(token) = token.as_ref().filter(|_| is_google_link(link.as_str())) {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let response = drive
+            .hub
+            .client
+            .request(request.body(Body::empty())?)
+            .await?;
+
+        // Export links redirect to the host actually serving the content
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .ok_or_else(|| anyhow!("Export link redirected without a location"))?
+                .to_str()?;
+            link = Url::parse(link.as_str())?.join(location)?.to_string();
+            continue;
+        }
+
+        if !response.status().is_success() {
+            bail!("Export link responded with {}", response.status());
+        }
+        return Ok(response.into_body());
+    }
 
-use tokio::spawn;
+    bail!("Too many redirects while following the export link")
+}
+
+// Export links fail like any other download, so they get the same attempts
+async fn write_export_link<F, Fut>(file_manager: &mut FileManager, fetch: F) -> Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<Body>>,
+{
+    let mut last_error = None;
+
+    for attempt in 1..=MAX_DOWNLOAD_ATTEMPTS {
+        let written = match fetch().await {
+            std::result::Result::Ok(body) => file_manager.write_body(body).await,
+            Err(e) => Err(e),
+        };
+
+        match written {
+            std::result::Result::Ok(_) => return Ok(()),
+            Err(e) => {
+                println!(
+                    "{} | Export link attempt {} failed: {e:?}",
+                    file_manager.file_name, attempt
+                );
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.unwrap())
+}
+
+enum ContentClaim {
+    // Copied over from a file of the same request with the same content
+    Copied,
+    // Held while this file downloads the content, files with the same content wait for it
+    Claimed(Option<OwnedMutexGuard<Option<FileManager>>>),
+}
+
+impl ContentClaim {
+    // Lets the files waiting for the content copy it from this one
+    fn release(self, file_manager: &FileManager) {
+        if let ContentClaim::Claimed(Some(mut content_download)) = self {
+            *content_download = Some(file_manager.clone());
+        }
+    }
+}
 
-use crate::{link::Link, list::get_file_list, DriveManager};
+// A file ID or md5Checksum met several times within a request is only downloaded once
+// Claims which are dropped without being released let the next file download the content itself
+async fn claim_content(
+    session: &DownloadSession,
+    file_manager: &mut FileManager,
+) -> Result<ContentClaim> {
+    if file_manager.is_cached {
+        return Ok(ContentClaim::Claimed(None));
+    }
+    let content_lock = match session.get_content_lock(file_manager) {
+        Some(content_lock) => content_lock,
+        None => return Ok(ContentClaim::Claimed(None)),
+    };
+
+    let content_download = content_lock.lock_owned().await;
+    match content_download.as_ref() {
+        Some(source) => {
+            file_manager.copy_from(source).await?;
+            Ok(ContentClaim::Copied)
+        }
+        None => Ok(ContentClaim::Claimed(Some(content_download))),
+    }
+}
 
 async fn download_mormal_file(
     drive: Arc<DriveManager>,
     file_metadata: File,
-    downloaded_files: Arc<Mutex<Vec<FileManager>>>,
+    folder_path: String,
+    session: Arc<DownloadSession>,
 ) {
-    let file_manager = FileManager::new(
+    let mut file_manager = FileManager::new(
         file_metadata.clone(),
         drive.cache.clone(),
-        "tmp/files".to_string(),
+        session.base_path.clone(),
+        folder_path,
+        None,
+        session.options.processing.clone(),
     );
 
-    // Download if not already cached
-    if !file_manager.is_cached.clone() {
-        // Get the file contents
-        let (response, _) = drive
-            .hub
-            .clone()
-            .files()
-            .get(file_metadata.id.clone().unwrap().as_str())
-            .add_scope("https://www.googleapis.com/auth/drive.readonly")
-            .param("alt", "media")
-            .supports_all_drives(true)
-            .acknowledge_abuse(true)
-            .doit()
-            .await
-            .expect(
-                format!(
-                    "{} | Unable to download",
-                    file_metadata.name.clone().unwrap()
-                )
-                .as_str(),
-            );
+    // Skip files which would not end up in the requested output
+    if !session.options.includes(&file_manager) {
+        return;
+    }
+
+    let content_claim = match claim_content(&session, &mut file_manager).await {
+        std::result::Result::Ok(content_claim) => content_claim,
+        Err(e) => {
+            session.report_error(&file_metadata, format!("Unable to copy duplicate: {}", e));
+            return;
+        }
+    };
+
+    // Download if not already cached or downloaded by this request
+    if !file_manager.is_cached && !matches!(content_claim, ContentClaim::Copied) {
+        let mut last_error = None;
+
+        for attempt in 1..=MAX_DOWNLOAD_ATTEMPTS {
+            // Get the file contents, of the requested revision if any
+            let file_id = file_metadata.id.clone().unwrap();
+            let response = match session.options.revision.as_ref() {
+                Some(revision_id) => drive
+                    .hub
+                    .clone()
+                    .revisions()
+                    .get(file_id.as_str(), revision_id.as_str())
+                    .add_scope(DRIVE_READONLY_SCOPE)
+                    .param("alt", "media")
+                    .acknowledge_abuse(true)
+                    .doit()
+                    .await
+                    .map(|(response, _)| response),
+                None => drive
+                    .hub
+                    .clone()
+                    .files()
+                    .get(file_id.as_str())
+                    .add_scope(DRIVE_READONLY_SCOPE)
+                    .param("alt", "media")
+                    .supports_all_drives(true)
+                    .acknowledge_abuse(true)
+                    .doit()
+                    .await
+                    .map(|(response, _)| response),
+            };
+
+            let file_bytes = match response {
+                std::result::Result::Ok(response) => response
+                    .collect()
+                    .await
+                    .map(|body| body.to_bytes())
+                    .map_err(anyhow::Error::from),
+                Err(e) => Err(anyhow!(e.to_string())),
+            };
+
+            // Only content matching what Drive reports is allowed into the cache
+            match file_bytes
+                .and_then(|file_bytes| file_manager.verify_content(&file_bytes).map(|_| file_bytes))
+            {
+                std::result::Result::Ok(file_bytes) => {
+                    // Write to disk
+                    file_manager.write_file(file_bytes).await.unwrap();
+                    last_error = None;
+                    break;
+                }
+                Err(e) => {
+                    println!(
+                        "{} | Download attempt {} failed: {e:?}",
+                        file_metadata.name.clone().unwrap(),
+                        attempt
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
 
-        // Write to disk
-        let file_bytes = response.collect().await.unwrap().to_bytes();
-        file_manager.write_file(file_bytes).await.unwrap();
+        if let Some(e) = last_error {
+            session.report_error(&file_metadata, format!("Unable to download: {}", e));
+            return;
+        }
         println!("DOWNLOADED FILE - {:#?}", file_metadata.id.unwrap());
     }
 
+    content_claim.release(&file_manager);
     // Append to list of downloaded files
-    downloaded_files.lock().unwrap().push(file_manager);
+    session.downloaded_files.lock().unwrap().push(file_manager);
 }
 
 async fn download_workspace_file(
     drive: Arc<DriveManager>,
     file_metadata: File,
-    downloaded_files: Arc<Mutex<Vec<FileManager>>>,
+    export_format: Option<ExportFormat>,
+    folder_path: String,
+    session: Arc<DownloadSession>,
 ) {
-    let file_manager = FileManager::new(
+    let mut file_manager = FileManager::new(
         file_metadata.clone(),
         drive.cache.clone(),
-        "tmp/files".to_string(),
+        session.base_path.clone(),
+        folder_path,
+        export_format,
+        session.options.processing.clone(),
     );
 
-    // Only download if not already cached
-    if !file_manager.is_cached.clone() {
-        let new_mime_type = file_manager.mime_type.clone();
+    // Skip files which would not end up in the requested output
+    if !session.options.includes(&file_manager) {
+        return;
+    }
+
+    let content_claim = match claim_content(&session, &mut file_manager).await {
+        std::result::Result::Ok(content_claim) => content_claim,
+        Err(e) => {
+            session.report_error(&file_metadata, format!("Unable to copy duplicate: {}", e));
+            return;
+        }
+    };
+
+    if matches!(content_claim, ContentClaim::Copied) {
+        // Already exported by this request
+    } else if file_manager.is_placeholder {
+        // Types without any export format are replaced by a link to the file
+        println!(
+            "{} | File format not currently supported by FilesTiK, writing a link instead",
+            file_metadata.name.clone().unwrap()
+        );
+        file_manager.write_placeholder().await.unwrap();
+    } else if !file_manager.is_cached && session.options.revision.is_some() {
+        let file_id = file_metadata.id.clone().unwrap();
+        let revision_id = session.options.revision.clone().unwrap();
+        let mime_type = file_manager.mime_type.clone();
+
+        let written = write_export_link(&mut file_manager, || {
+            download_revision_export_link(
+                drive.clone(),
+                file_id.as_str(),
+                revision_id.as_str(),
+                mime_type.as_str(),
+            )
+        })
+        .await;
 
-        if new_mime_type.is_empty() {
-            println!("File format not currently supported by FilesTiK")
+        if let Err(e) = written {
+            session.report_error(
+                &file_metadata,
+                format!("Unable to export the revision: {}", e),
+            );
+            return;
         }
+        println!("DOWNLOADED FILE - {:#?}", file_metadata.id.clone().unwrap());
+    } else if !file_manager.is_cached {
+        // Only download if not already cached
+        let new_mime_type = file_manager.mime_type.clone();
 
         // Get the file contents
         let response = drive
@@ -82,61 +378,162 @@ async fn download_workspace_file(
                 file_metadata.id.clone().unwrap().as_str(),
                 new_mime_type.as_str(),
             )
-            .add_scope("https://www.googleapis.com/auth/drive.readonly")
+            .add_scope(DRIVE_READONLY_SCOPE)
             .param("alt", "media")
             .doit()
-            .await
-            .expect(
-                format!(
-                    "{} | Unable to download",
-                    file_metadata.name.clone().unwrap()
-                )
-                .as_str(),
-            );
-
-        // Write to disk
-        let file_bytes = response.collect().await.unwrap().to_bytes();
-        file_manager.write_file(file_bytes).await.unwrap();
+            .await;
+
+        match response {
+            std::result::Result::Ok(response) => {
+                // Write to disk
+                let file_bytes = response.collect().await.unwrap().to_bytes();
+                file_manager.write_file(file_bytes).await.unwrap();
+            }
+            Err(e) if is_export_size_limit_error(&e) => {
+                // Stream large exports to disk through the export link instead
+                let file_id = file_metadata.id.clone().unwrap();
+                let written = write_export_link(&mut file_manager, || {
+                    download_export_link(drive.clone(), file_id.as_str(), new_mime_type.as_str())
+                })
+                .await;
+
+                if let Err(e) = written {
+                    session.report_error(
+                        &file_metadata,
+                        format!("Unable to download through export link: {}", e),
+                    );
+                    return;
+                }
+            }
+            Err(e) => {
+                session.report_error(&file_metadata, format!("Unable to export: {}", e));
+                return;
+            }
+        }
         println!("DOWNLOADED FILE - {:#?}", file_metadata.id.unwrap());
     }
 
+    content_claim.release(&file_manager);
     // Append to list of downloaded files
-    downloaded_files.lock().unwrap().push(file_manager);
+    session.downloaded_files.lock().unwrap().push(file_manager);
+}
+
+// Status code and reason Drive answered a failed request with, eg. `(404, "notFound")`
+pub(crate) fn get_api_error(error: &anyhow::Error) -> Option<(u64, String)> {
+    let error = error.to_string();
+    let error_value = serde_json::from_str::<Value>(error[error.find('{')?..].trim()).ok()?;
+    let code = error_value["error"]["code"].as_u64()?;
+    let reason = error_value["error"]["errors"][0]["reason"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    Some((code, reason))
+}
+
+// Drive reports exhausted quotas as 403 or 429 errors which succeed when retried later
+pub(crate) fn is_rate_limit_error(error: &anyhow::Error) -> bool {
+    match get_api_error(error) {
+        Some((429, _)) => true,
+        Some((403, reason)) => {
+            ["rateLimitExceeded", "userRateLimitExceeded"].contains(&reason.as_str())
+        }
+        _ => false,
+    }
+}
+
+pub(crate) fn get_shortcut_target_id(shortcut: &File) -> Option<String> {
+    shortcut
+        .shortcut_details
+        .as_ref()
+        .and_then(|shortcut_details| shortcut_details.target_id.clone())
+}
+
+// Stands in for the shortcut so that it is written as a placeholder linking to the target
+pub(crate) fn get_shortcut_link_file(shortcut: &File, target_id: String) -> File {
+    File {
+        id: Some(target_id),
+        name: shortcut.name.clone(),
+        mime_type: shortcut.mime_type.clone(),
+        ..Default::default()
+    }
+}
+
+// Folder names can contain slashes which would otherwise create extra directories
+pub(crate) fn join_folder_path(folder_path: String, folder_name: String) -> String {
+    let folder_name = folder_name.replace('/', "_");
+    if folder_path.is_empty() {
+        folder_name
+    } else {
+        format!("{}/{}", folder_path, folder_name)
+    }
 }
 
 #[async_recursion]
 async fn download_folder(
     drive: Arc<DriveManager>,
     folder_id: String,
+    folder_path: String,
+    ancestor_ids: Vec<String>,
     page_token: Option<String>,
-    downloaded_files: Arc<Mutex<Vec<FileManager>>>,
+    session: Arc<DownloadSession>,
 ) {
-    let filter = format!("'{}' in parents and trashed=false", folder_id);
-    let file_list = get_file_list(
+    // Deletions are told apart by the files listed, so filtered out files have to be listed too
+    // They are left out by `includes_file` instead
+    let filter_query = if session.options.incremental.manifest.is_empty() {
+        session.options.filters.get_query()
+    } else {
+        String::new()
+    };
+    let filter = format!(
+        "'{}' in parents and trashed=false{}",
+        folder_id, filter_query
+    );
+    let file_list = match get_file_list(
         drive.clone(),
         Some(filter.as_str()),
         Some(page_token.unwrap_or_default().as_str()),
         None,
     )
     .await
-    .unwrap();
+    {
+        std::result::Result::Ok(file_list) => file_list,
+        Err(e) => {
+            // The folder is reported instead of failing the whole download
+            let folder = File {
+                id: Some(folder_id),
+                name: Some(match folder_path.rsplit_once('/') {
+                    Some((_, folder_name)) => folder_name.to_string(),
+                    None if folder_path.is_empty() => session.root.name.clone().unwrap_or_default(),
+                    None => folder_path.clone(),
+                }),
+                ..Default::default()
+            };
+            session.report_error(&folder, format!("Could not list the folder: {}", e));
+            session.skip_folder(folder_path);
+            return;
+        }
+    };
 
     let mut thread_handlers = vec![];
 
-    for f in file_list.files.unwrap() {
+    for f in file_list.files.unwrap_or_default() {
         thread_handlers.push(spawn(segregate_downloads(
             drive.clone(),
             f,
-            downloaded_files.clone(),
+            folder_path.clone(),
+            ancestor_ids.clone(),
+            session.clone(),
         )));
     }
 
-    if !file_list.next_page_token.is_none() {
+    if file_list.next_page_token.is_some() {
         download_folder(
             drive,
             folder_id,
+            folder_path,
+            ancestor_ids,
             file_list.next_page_token,
-            downloaded_files,
+            session,
         )
         .await;
     }
@@ -148,16 +545,67 @@ async fn download_folder(
 async fn segregate_downloads(
     drive: Arc<DriveManager>,
     file_metadata: File,
-    downloaded_files: Arc<Mutex<Vec<FileManager>>>,
+    folder_path: String,
+    // Folders from the root of the download down to the one containing the file
+    ancestor_ids: Vec<String>,
+    session: Arc<DownloadSession>,
 ) {
-    match file_metadata.mime_type.clone().unwrap() {
+    // Files still on Drive are not deletions, even when they are left out of the output
+    let mime_type = file_metadata.mime_type.clone().unwrap();
+    if mime_type != "application/vnd.google-apps.folder"
+        && mime_type != "application/vnd.google-apps.shortcut"
+    {
+        session
+            .seen_file_ids
+            .lock()
+            .unwrap()
+            .insert(file_metadata.id.clone().unwrap_or_default());
+    }
+
+    match mime_type {
         // Handle folders
-        mime_type if mime_type == String::from("application/vnd.google-apps.folder") => {
+        mime_type if mime_type == "application/vnd.google-apps.folder" => {
+            let folder_name = file_metadata.name.clone().unwrap_or_default();
+            let folder_path = join_folder_path(folder_path, folder_name.clone());
+            if !session.options.filters.includes_folder(
+                &folder_path,
+                &session.root_path,
+                &folder_name,
+            ) {
+                session.skip_folder(folder_path);
+                return;
+            }
+            if DownloadFilters::get_depth(&folder_path, &session.root_path) > drive.limits.max_depth
+            {
+                session.skip_folder(folder_path.clone());
+                session.report_error(
+                    &file_metadata,
+                    format!(
+                        "Folder is nested deeper than {} levels",
+                        drive.limits.max_depth
+                    ),
+                );
+                return;
+            }
+            // Shortcuts can lead back into a folder containing them, other folders reached twice are downloaded twice
+            let folder_id = file_metadata.id.clone().unwrap();
+            if ancestor_ids.contains(&folder_id) {
+                session.report_error(
+                    &file_metadata,
+                    "Folder is a shortcut back to a folder containing it".to_string(),
+                );
+                return;
+            }
+            let mut ancestor_ids = ancestor_ids;
+            ancestor_ids.push(folder_id.clone());
+
             spawn(download_folder(
                 drive.clone(),
-                file_metadata.id.clone().unwrap(),
+                folder_id,
+                folder_path,
+                ancestor_ids,
                 None,
-                downloaded_files.clone(),
+                session.clone(),
             ))
             .await
             .unwrap();
@@ -166,31 +614,85 @@ async fn segregate_downloads(
 
         // Handle shortcuts
         mime_type if mime_type == "application/vnd.google-apps.shortcut" => {
-            let original_file = metadata(
-                drive.clone(),
-                file_metadata
-                    .shortcut_details
-                    .unwrap()
-                    .target_id
-                    .unwrap()
-                    .as_str(),
-                None,
-            )
-            .await
-            .unwrap();
-
-            segregate_downloads(drive.clone(), original_file, downloaded_files).await;
+            let target_id = match get_shortcut_target_id(&file_metadata) {
+                Some(target_id) => target_id,
+                None => {
+                    session.report_error(
+                        &file_metadata,
+                        "Shortcut does not have a target".to_string(),
+                    );
+                    return;
+                }
+            };
+
+            match session.options.shortcuts {
+                ShortcutPolicy::Skip => {}
+                ShortcutPolicy::Link => {
+                    download_workspace_file(
+                        drive.clone(),
+                        get_shortcut_link_file(&file_metadata, target_id),
+                        None,
+                        folder_path,
+                        session,
+                    )
+                    .await;
+                }
+                ShortcutPolicy::Follow => {
+                    // Targets which were deleted or are not shared anymore leave broken shortcuts behind
+                    match metadata(drive.clone(), target_id.as_str(), None).await {
+                        std::result::Result::Ok(original_file) => {
+                            segregate_downloads(
+                                drive.clone(),
+                                original_file,
+                                folder_path,
+                                ancestor_ids,
+                                session,
+                            )
+                            .await
+                        }
+                        Err(e) => session.report_error(
+                            &file_metadata,
+                            format!("Shortcut target is not available: {}", e),
+                        ),
+                    }
+                }
+            }
         }
 
+        // Drop files the filters do not let through
+        _ if !session
+            .options
+            .filters
+            .includes_file(&file_metadata, &folder_path) => {}
+
+        // Drop files which did not change since the earlier download
+        _ if !session.options.incremental.has_changed(&file_metadata) => {}
+
         // Handle workspace files
         mime_type if mime_type.starts_with("application/vnd.google-apps") => {
-            spawn(download_workspace_file(
-                drive.clone(),
-                file_metadata.clone(),
-                downloaded_files.clone(),
-            ))
-            .await
-            .unwrap();
+            // The same file is exported once for every requested format
+            let mut export_formats = session
+                .options
+                .export_formats
+                .get(mime_type.as_str())
+                .iter()
+                .map(|format| Some(*format))
+                .collect::<Vec<_>>();
+            if export_formats.is_empty() {
+                export_formats.push(None);
+            }
+
+            let mut thread_handlers = vec![];
+            for export_format in export_formats {
+                thread_handlers.push(spawn(download_workspace_file(
+                    drive.clone(),
+                    file_metadata.clone(),
+                    export_format,
+                    folder_path.clone(),
+                    session.clone(),
+                )));
+            }
+            join_all(thread_handlers).await;
             return;
         }
 
@@ -199,7 +701,8 @@ async fn segregate_downloads(
             spawn(download_mormal_file(
                 drive.clone(),
                 file_metadata,
-                downloaded_files.clone(),
+                folder_path,
+                session.clone(),
             ))
             .await
             .unwrap();
@@ -215,7 +718,7 @@ pub async fn metadata(
 ) -> Result<File> {
     let cache_key = DriveManager::get_call_hash(
         "file.get",
-        String::new(),
+        file_id.to_string(),
         String::new(),
         custom_fields.unwrap_or("").to_string(),
     );
@@ -225,18 +728,32 @@ pub async fn metadata(
         .unwrap()
         .get_from_redis::<RedisRequest<File>>(cache_key.clone());
 
-    if redis_response.is_ok() {
-        let file_metadata = redis_response.unwrap().data;
+    if let std::result::Result::Ok(redis_response) = redis_response {
+        let file_metadata = redis_response.data;
         return Ok(file_metadata);
     }
 
-    if redis_response.is_ok() {
-        let file_list = redis_response.unwrap().data;
-        return Ok(file_list);
-    }
+    let file_metadata = live_metadata(drive.clone(), file_id, custom_fields).await?;
 
+    drive.cache.lock().unwrap().set_to_redis_with_ttl(
+        cache_key.clone(),
+        RedisRequest {
+            data: file_metadata.clone(),
+        },
+        DRIVE_CALL_CACHE_TTL_SECS,
+    );
+
+    Ok(file_metadata)
+}
+
+// Skips the cache, for callers which have to see the file as Drive has it right now
+pub async fn live_metadata(
+    drive: Arc<DriveManager>,
+    file_id: &str,
+    custom_fields: Option<&str>,
+) -> Result<File> {
     let fields = custom_fields.unwrap_or(
-        "shortcutDetails, mimeType, name, id, fileExtension, headRevisionId, webViewLink",
+        "shortcutDetails, mimeType, name, id, fileExtension, headRevisionId, webViewLink, md5Checksum, size, modifiedTime, version, description, properties, owners(displayName, emailAddress)",
     );
     let (_, file_metadata) = drive
         .hub
@@ -245,14 +762,7 @@ pub async fn metadata(
         .param("fields", fields)
         .doit()
         .await
-        .unwrap();
-
-    drive.cache.lock().unwrap().set_to_redis(
-        cache_key.clone(),
-        RedisRequest {
-            data: file_metadata.clone(),
-        },
-    );
+        .map_err(|e| anyhow!(e.to_string()))?;
 
     Ok(file_metadata)
 }
@@ -260,14 +770,250 @@ pub async fn metadata(
 pub async fn universal(
     drive: Arc<DriveManager>,
     url: &str,
-) -> Result<Arc<Mutex<Vec<FileManager>>>> {
+    options: DownloadOptions,
+) -> Result<Arc<DownloadSession>> {
+    universal_into(
+        drive,
+        url,
+        options,
+        None,
+        TMP_FILES_UNCOMPRESSED_BASE_PATH.to_string(),
+    )
+    .await
+}
+
+// Downloads the link into `folder_path` of the output, the contents of linked folders end up directly inside it
+pub(crate) async fn universal_into(
+    drive: Arc<DriveManager>,
+    url: &str,
+    options: DownloadOptions,
+    folder_path: Option<String>,
+    base_path: String,
+) -> Result<Arc<DownloadSession>> {
     let link = Link::new(url.to_string());
-    let downloaded_files = Arc::new(Mutex::new(Vec::new()));
 
-    // Get the metadata
-    let file_metadata = metadata(drive.clone(), &link.id, None).await.unwrap();
+    // Get the metadata, of the requested revision if any
+    let file_metadata = match options.revision.as_ref() {
+        Some(revision_id) => {
+            let file_metadata = resolve_file(drive.clone(), &link.id).await?;
+            if file_metadata.mime_type.clone().unwrap_or_default()
+                == "application/vnd.google-apps.folder"
+            {
+                bail!("Folders do not have revisions");
+            }
+            apply_revision(drive.clone(), file_metadata, revision_id).await?
+        }
+        None => metadata(drive.clone(), &link.id, None).await?,
+    };
+    let session = Arc::new(DownloadSession {
+        base_path,
+        root_path: folder_path.clone().unwrap_or_default(),
+        ..DownloadSession::new(options, file_metadata.clone())
+    });
+
+    // The contents of the linked folder are placed at the root of the output
+    if file_metadata.mime_type.clone().unwrap_or_default() == "application/vnd.google-apps.folder" {
+        let folder_id = file_metadata.id.unwrap();
+        download_folder(
+            drive.clone(),
+            folder_id.clone(),
+            folder_path.unwrap_or_default(),
+            vec![folder_id],
+            None,
+            session.clone(),
+        )
+        .await;
+    } else {
+        segregate_downloads(
+            drive.clone(),
+            file_metadata,
+            folder_path.unwrap_or_default(),
+            Vec::new(),
+            session.clone(),
+        )
+        .await;
+    }
+
+    Ok(session)
+}
+
+// Downloads every link concurrently into a single session
+pub async fn multiple(
+    drive: Arc<DriveManager>,
+    links: Vec<DownloadLink>,
+    options: DownloadOptions,
+) -> Result<Arc<DownloadSession>> {
+    if options.revision.is_some() {
+        bail!("Revisions can only be requested for a single link");
+    }
+
+    let mut thread_handlers = vec![];
+    for (link_idx, link) in links.iter().enumerate() {
+        let drive = drive.clone();
+        let link = link.clone();
+        let options = options.clone();
+
+        thread_handlers.push(spawn(async move {
+            // Linked folders go into a folder of their own unless asked otherwise
+            let folder_path = match link.folder.as_ref() {
+                Some(folder) => Some(join_folder_path(String::new(), folder.clone())),
+                None => {
+                    let link_id = Link::new(link.link.clone()).id;
+                    let file_metadata = metadata(drive.clone(), &link_id, None).await?;
+                    match file_metadata.mime_type.clone().unwrap_or_default().as_str() {
+                        "application/vnd.google-apps.folder" => Some(join_folder_path(
+                            String::new(),
+                            file_metadata.name.unwrap_or_default(),
+                        )),
+                        _ => None,
+                    }
+                }
+            };
+
+            // Every link is written to disk separately so that equal names cannot overwrite each other
+            universal_into(
+                drive,
+                link.link.as_str(),
+                options,
+                folder_path,
+                format!("{}/{}", TMP_FILES_UNCOMPRESSED_BASE_PATH, link_idx),
+            )
+            .await
+        }));
+    }
+
+    let mut link_sessions = vec![];
+    for (link, link_session) in links.iter().zip(join_all(thread_handlers).await) {
+        link_sessions.push(link_session?.map_err(|e| DownloadError {
+            file_id: String::new(),
+            name: link.link.clone(),
+            error: e.to_string(),
+            row: None,
+        }));
+    }
+
+    Ok(merge_sessions("output", options, link_sessions))
+}
+
+// Collects the files and errors of every link into one session with unique paths
+pub(crate) fn merge_sessions(
+    name: &str,
+    options: DownloadOptions,
+    link_sessions: Vec<std::result::Result<Arc<DownloadSession>, DownloadError>>,
+) -> Arc<DownloadSession> {
+    let root = File {
+        name: Some(name.to_string()),
+        mime_type: Some("application/vnd.google-apps.folder".to_string()),
+        ..Default::default()
+    };
+    let session = Arc::new(DownloadSession::new(options, root));
+    let mut downloaded_files = vec![];
+
+    for link_session in link_sessions {
+        match link_session {
+            std::result::Result::Ok(link_session) => {
+                downloaded_files.extend(link_session.downloaded_files.lock().unwrap().drain(..));
+                session
+                    .seen_file_ids
+                    .lock()
+                    .unwrap()
+                    .extend(link_session.seen_file_ids.lock().unwrap().drain());
+                session
+                    .skipped_folder_paths
+                    .lock()
+                    .unwrap()
+                    .extend(link_session.skipped_folder_paths.lock().unwrap().drain(..));
+                session
+                    .errors
+                    .lock()
+                    .unwrap()
+                    .extend(link_session.errors.lock().unwrap().drain(..));
+            }
+            Err(error) => {
+                // Where the files of the link would have gone is unknown
+                session.skip_folder(String::new());
+                session.errors.lock().unwrap().push(error);
+            }
+        }
+    }
+
+    FileManager::deduplicate_relative_paths(&mut downloaded_files);
+    *session.downloaded_files.lock().unwrap() = downloaded_files;
 
-    segregate_downloads(drive.clone(), file_metadata, downloaded_files.clone()).await;
+    session
+}
+
+// Metadata of the file behind the ID, resolving shortcuts on the way
+pub async fn resolve_file(drive: Arc<DriveManager>, file_id: &str) -> Result<File> {
+    let file_metadata = metadata(drive.clone(), file_id, None).await?;
+    if file_metadata.mime_type.clone().unwrap_or_default() != "application/vnd.google-apps.shortcut"
+    {
+        return Ok(file_metadata);
+    }
+
+    let target_id = file_metadata
+        .shortcut_details
+        .and_then(|shortcut_details| shortcut_details.target_id)
+        .ok_or_else(|| anyhow!("Shortcut does not have a target"))?;
+    metadata(drive, target_id.as_str(), None).await
+}
+
+// Metadata of the file a single download serves, of the requested revision if any
+async fn single_metadata(
+    drive: Arc<DriveManager>,
+    file_id: &str,
+    options: &DownloadOptions,
+) -> Result<File> {
+    let file_metadata = resolve_file(drive.clone(), file_id).await?;
+    if file_metadata.mime_type.clone().unwrap_or_default() == "application/vnd.google-apps.folder" {
+        bail!("Folders cannot be served as a single file");
+    }
+    match options.revision.as_ref() {
+        Some(revision_id) => apply_revision(drive, file_metadata, revision_id).await,
+        None => Ok(file_metadata),
+    }
+}
+
+// Describes the file a single download would serve without downloading it
+pub async fn describe_single(
+    drive: Arc<DriveManager>,
+    file_id: &str,
+    options: &DownloadOptions,
+) -> Result<FileManager> {
+    let file_metadata = single_metadata(drive.clone(), file_id, options).await?;
+    let export_format = options
+        .export_formats
+        .get(file_metadata.mime_type.clone().unwrap_or_default().as_str())
+        .first()
+        .copied();
+
+    Ok(FileManager::new(
+        file_metadata,
+        drive.cache.clone(),
+        TMP_FILES_UNCOMPRESSED_BASE_PATH.to_string(),
+        String::new(),
+        export_format,
+        options.processing.clone(),
+    ))
+}
+
+// Downloads only the file the link points at
+pub async fn single(
+    drive: Arc<DriveManager>,
+    file_id: &str,
+    options: DownloadOptions,
+) -> Result<Arc<DownloadSession>> {
+    let file_metadata = single_metadata(drive.clone(), file_id, &options).await?;
+
+    let session = Arc::new(DownloadSession::new(options, file_metadata.clone()));
+    segregate_downloads(
+        drive.clone(),
+        file_metadata,
+        String::new(),
+        Vec::new(),
+        session.clone(),
+    )
+    .await;
 
-    Ok(downloaded_files)
+    Ok(session)
 }
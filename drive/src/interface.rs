@@ -1,6 +1,31 @@
-use std::fs::File;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    fs::File,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
 
+use anyhow::{anyhow, bail, Ok, Result};
+use chrono::{DateTime, Utc};
+use fs::{
+    interface::{
+        ArchiveOptions, DownloadError, ExportFormats, MergeOptions, OutputMode, ProcessingOptions,
+    },
+    manifest::ManifestEntry,
+    FileManager, TMP_FILES_OUTPUT_BASE_PATH, TMP_FILES_UNCOMPRESSED_BASE_PATH,
+};
+use futures::channel::mpsc;
+use glob::Pattern;
+use google_drive3::{api::File as DriveFile, hyper::body::Bytes};
 use mime_guess::Mime;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex as AsyncMutex;
+
+static OUTPUT_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 pub struct CreateFileStruct {
     pub file_path: String,
@@ -10,4 +35,851 @@ pub struct CreateFileStruct {
     pub parents: Vec<String>,
     pub content: File,
     pub file_id: Option<String>,
+    // Uploads the content in chunks through a session that survives failures
+    pub resumable: bool,
+}
+
+// A file of an upload request which did not make it to Drive
+#[derive(Clone, Debug, Serialize)]
+pub struct UploadError {
+    pub name: String,
+    pub error: String,
+}
+
+pub struct UploadBatch {
+    pub urls: Vec<Option<String>>,
+    pub errors: Vec<UploadError>,
+}
+
+// A single file ready to be served as is
+pub struct SingleFile {
+    pub file_manager: FileManager,
+    pub path: String,
+}
+
+// Response of Drive being passed through to the client
+pub struct DriveStream {
+    pub status: u16,
+    pub mime_type: String,
+    pub content_length: Option<String>,
+    pub content_range: Option<String>,
+    pub body: mpsc::Receiver<std::io::Result<Bytes>>,
+}
+
+pub enum StreamSource {
+    Cached(Box<SingleFile>),
+    Drive(DriveStream),
+}
+
+static FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+static SHORTCUT_MIME_TYPE: &str = "application/vnd.google-apps.shortcut";
+static DEFAULT_MAX_FOLDER_DEPTH: usize = 32;
+static DEFAULT_RESUMABLE_UPLOAD_THRESHOLD: u64 = 5 * 1024 * 1024;
+static DEFAULT_UPLOAD_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+static DEFAULT_UPLOAD_CHUNK_ATTEMPTS: u32 = 5;
+// Drive only accepts chunks in multiples of this size, except for the last one
+pub static UPLOAD_CHUNK_ALIGNMENT: u64 = 256 * 1024;
+
+// Limits a download to a part of the folder tree
+// Patterns containing a `/` are matched against the path inside the download, every other one against the name
+#[derive(Clone, Default, Debug)]
+pub struct DownloadFilters {
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+    pub mime_types: Vec<Pattern>,
+    pub exclude_mime_types: Vec<Pattern>,
+    // Only binary files have a size, Workspace files always pass these
+    pub min_size: Option<i64>,
+    pub max_size: Option<i64>,
+    pub modified_after: Option<DateTime<Utc>>,
+    pub modified_before: Option<DateTime<Utc>>,
+    // Email address of the owner
+    pub owner: Option<String>,
+    // Number of folders below the linked one which are still walked, 0 only keeps its direct children
+    pub max_depth: Option<usize>,
+}
+
+impl DownloadFilters {
+    pub fn parse_patterns(patterns: &str) -> Result<Vec<Pattern>> {
+        patterns
+            .split(',')
+            .map(|pattern| pattern.trim())
+            .filter(|pattern| !pattern.is_empty())
+            .map(|pattern| {
+                Pattern::new(pattern).map_err(|e| anyhow!("Invalid pattern `{}`: {}", pattern, e))
+            })
+            .collect()
+    }
+
+    pub fn parse_time(time: &str) -> Result<DateTime<Utc>> {
+        Ok(DateTime::parse_from_rfc3339(time)
+            .map_err(|e| anyhow!("Invalid time `{}`: {}", time, e))?
+            .with_timezone(&Utc))
+    }
+
+    fn escape(value: &str) -> String {
+        value.replace('\\', "\\\\").replace('\'', "\\'")
+    }
+
+    fn matches_pattern(pattern: &Pattern, path: &str, name: &str) -> bool {
+        if pattern.as_str().contains('/') {
+            pattern.matches(path)
+        } else {
+            pattern.matches(name)
+        }
+    }
+
+    // Conditions Drive can evaluate itself, appended to the `q` of folder listings
+    // Folders and shortcuts always have to be listed so that they can be followed
+    pub fn get_query(&self) -> String {
+        let mut conditions = Vec::new();
+
+        let is_exact = |pattern: &&Pattern| !pattern.as_str().contains(['*', '?', '[']);
+        if !self.mime_types.is_empty() && self.mime_types.iter().all(|pattern| is_exact(&pattern)) {
+            conditions.push(format!(
+                "({})",
+                self.mime_types
+                    .iter()
+                    .map(|pattern| format!("mimeType = '{}'", Self::escape(pattern.as_str())))
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            ));
+        }
+        for pattern in self.exclude_mime_types.iter().filter(is_exact) {
+            conditions.push(format!("mimeType != '{}'", Self::escape(pattern.as_str())));
+        }
+        if let Some(modified_after) = self.modified_after {
+            conditions.push(format!("modifiedTime > '{}'", modified_after.to_rfc3339()));
+        }
+        if let Some(modified_before) = self.modified_before {
+            conditions.push(format!("modifiedTime < '{}'", modified_before.to_rfc3339()));
+        }
+        if let Some(owner) = self.owner.as_ref() {
+            conditions.push(format!("'{}' in owners", Self::escape(owner)));
+        }
+
+        if conditions.is_empty() {
+            return String::new();
+        }
+        format!(
+            " and (mimeType = '{}' or mimeType = '{}' or ({}))",
+            FOLDER_MIME_TYPE,
+            SHORTCUT_MIME_TYPE,
+            conditions.join(" and ")
+        )
+    }
+
+    // Depth of the folder counted from the linked one, which is placed at `root_path` of the output
+    pub fn get_depth(folder_path: &str, root_path: &str) -> usize {
+        let relative_path = match folder_path.strip_prefix(root_path) {
+            Some("") => "",
+            Some(relative_path) if root_path.is_empty() => relative_path,
+            Some(relative_path) => relative_path.strip_prefix('/').unwrap_or(folder_path),
+            None => folder_path,
+        };
+        if relative_path.is_empty() {
+            0
+        } else {
+            relative_path.split('/').count()
+        }
+    }
+
+    // Whether the folder `folder_path` is walked at all
+    pub fn includes_folder(&self, folder_path: &str, root_path: &str, name: &str) -> bool {
+        if let Some(max_depth) = self.max_depth {
+            if Self::get_depth(folder_path, root_path) > max_depth {
+                return false;
+            }
+        }
+        !self
+            .exclude
+            .iter()
+            .any(|pattern| Self::matches_pattern(pattern, folder_path, name))
+    }
+
+    pub fn includes_file(&self, file: &DriveFile, folder_path: &str) -> bool {
+        let name = file.name.clone().unwrap_or_default();
+        let path = if folder_path.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", folder_path, name.replace('/', "_"))
+        };
+        let mime_type = file.mime_type.clone().unwrap_or_default();
+
+        if !self.include.is_empty()
+            && !self
+                .include
+                .iter()
+                .any(|pattern| Self::matches_pattern(pattern, &path, &name))
+        {
+            return false;
+        }
+        if self
+            .exclude
+            .iter()
+            .any(|pattern| Self::matches_pattern(pattern, &path, &name))
+        {
+            return false;
+        }
+        if !self.mime_types.is_empty()
+            && !self
+                .mime_types
+                .iter()
+                .any(|pattern| pattern.matches(&mime_type))
+        {
+            return false;
+        }
+        if self
+            .exclude_mime_types
+            .iter()
+            .any(|pattern| pattern.matches(&mime_type))
+        {
+            return false;
+        }
+        if let Some(size) = file.size {
+            if self.min_size.is_some_and(|min_size| size < min_size)
+                || self.max_size.is_some_and(|max_size| size > max_size)
+            {
+                return false;
+            }
+        }
+        if let Some(modified_time) = file.modified_time {
+            if self
+                .modified_after
+                .is_some_and(|modified_after| modified_time <= modified_after)
+                || self
+                    .modified_before
+                    .is_some_and(|modified_before| modified_time >= modified_before)
+            {
+                return false;
+            }
+        }
+        if let Some(owner) = self.owner.as_ref() {
+            let is_owner = file.owners.iter().flatten().any(|user| {
+                user.email_address
+                    .as_ref()
+                    .is_some_and(|email_address| email_address.eq_ignore_ascii_case(owner))
+            });
+            if !is_owner {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// What happens to shortcuts met inside downloaded folders
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ShortcutPolicy {
+    // Downloads the target in place of the shortcut
+    #[default]
+    Follow,
+    Skip,
+    // Writes a link file pointing at the target
+    Link,
+}
+
+// Earlier state of a download so that only what changed since gets downloaded again
+#[derive(Clone, Default, Debug)]
+pub struct IncrementalOptions {
+    pub modified_since: Option<DateTime<Utc>>,
+    // Entries of the manifest of an earlier archive by file ID
+    // Workspace files exported to several formats have an entry for every format
+    pub manifest: BTreeMap<String, Vec<ManifestEntry>>,
+}
+
+impl IncrementalOptions {
+    pub fn new(
+        modified_since: Option<DateTime<Utc>>,
+        manifest: Option<Vec<ManifestEntry>>,
+    ) -> Self {
+        let mut manifest_by_id = BTreeMap::<String, Vec<ManifestEntry>>::new();
+        for entry in manifest.unwrap_or_default() {
+            manifest_by_id
+                .entry(entry.file_id.clone())
+                .or_default()
+                .push(entry);
+        }
+
+        Self {
+            modified_since,
+            manifest: manifest_by_id,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.modified_since.is_some() || !self.manifest.is_empty()
+    }
+
+    // Files of the earlier manifest are compared by revision and modification time, new files always changed
+    pub fn has_changed(&self, file: &DriveFile) -> bool {
+        let file_id = file.id.clone().unwrap_or_default();
+        if let Some(entries) = self.manifest.get(&file_id) {
+            return entries.iter().any(|entry| {
+                let modified_time = entry
+                    .modified_time
+                    .as_ref()
+                    .and_then(|modified_time| DateTime::parse_from_rfc3339(modified_time).ok())
+                    .map(|modified_time| modified_time.with_timezone(&Utc));
+                entry.revision_id != file.head_revision_id
+                    || modified_time.is_none()
+                    || modified_time != file.modified_time
+            });
+        }
+        if !self.manifest.is_empty() {
+            return true;
+        }
+
+        match (self.modified_since, file.modified_time) {
+            (Some(modified_since), Some(modified_time)) => modified_time > modified_since,
+            _ => true,
+        }
+    }
+
+    // Entries of the earlier manifest whose files were not seen anymore
+    // Files inside folders which were not walked may still exist, so they are never reported
+    pub fn get_deletions(
+        &self,
+        seen_file_ids: &HashSet<String>,
+        skipped_folder_paths: &[String],
+    ) -> Vec<ManifestEntry> {
+        self.manifest
+            .values()
+            .flatten()
+            .filter(|entry| !seen_file_ids.contains(&entry.file_id))
+            .filter(|entry| {
+                !skipped_folder_paths.iter().any(|folder_path| {
+                    folder_path.is_empty()
+                        || entry
+                            .archive_path
+                            .starts_with(format!("{}/", folder_path).as_str())
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    // Entries of the earlier manifest for files which are still there but were not downloaded again
+    pub fn get_unchanged(
+        &self,
+        seen_file_ids: &HashSet<String>,
+        downloaded_files: &[FileManager],
+    ) -> Vec<ManifestEntry> {
+        let downloaded_file_ids = downloaded_files
+            .iter()
+            .filter_map(|file| file.file.id.clone())
+            .collect::<HashSet<_>>();
+        self.manifest
+            .values()
+            .flatten()
+            .filter(|entry| {
+                seen_file_ids.contains(&entry.file_id)
+                    && !downloaded_file_ids.contains(&entry.file_id)
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct DownloadOptions {
+    pub processing: ProcessingOptions,
+    pub export_formats: ExportFormats,
+    pub output: OutputMode,
+    pub archive: ArchiveOptions,
+    pub merge: MergeOptions,
+    pub filters: DownloadFilters,
+    // Revision of the linked file to download instead of the head one
+    pub revision: Option<String>,
+    pub incremental: IncrementalOptions,
+    pub shortcuts: ShortcutPolicy,
+}
+
+impl DownloadOptions {
+    // Whether the file is part of the requested output at all
+    pub fn includes(&self, file_manager: &FileManager) -> bool {
+        match self.output {
+            OutputMode::Archive => true,
+            OutputMode::Pdf => file_manager.mime_type == "application/pdf",
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.output == OutputMode::Archive && self.archive.password.is_some()
+    }
+
+    // Every output gets a path of its own so that concurrent requests never serve each other's
+    pub fn get_output_path(&self) -> String {
+        format!(
+            "{}/{}-{}.{}",
+            TMP_FILES_OUTPUT_BASE_PATH,
+            Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+            OUTPUT_COUNTER.fetch_add(1, Ordering::Relaxed),
+            self.get_output_ext()
+        )
+    }
+
+    pub fn get_output_ext(&self) -> &'static str {
+        match self.output {
+            OutputMode::Archive => self.archive.get_output_format().get_ext(),
+            OutputMode::Pdf => "pdf",
+        }
+    }
+
+    pub fn get_output_mime_type(&self) -> &'static str {
+        match self.output {
+            OutputMode::Archive => self.archive.get_output_format().get_mime_type(),
+            OutputMode::Pdf => "application/pdf",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PreviewOutput {
+    // Path inside the output
+    pub path: String,
+    pub mime_type: String,
+    pub is_cached: bool,
+    pub is_placeholder: bool,
+    #[serde(skip)]
+    pub version_key: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PreviewNode {
+    pub name: String,
+    // Folder the node is placed in, the node itself for folders
+    pub path: String,
+    pub mime_type: String,
+    pub size: Option<i64>,
+    pub outputs: Vec<PreviewOutput>,
+    pub children: Vec<PreviewNode>,
+}
+
+// What a download would produce, gathered from metadata alone
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Preview {
+    pub tree: PreviewNode,
+    // Number of files inside the output
+    pub file_count: usize,
+    // Number of Drive files per Drive MIME type
+    pub files_by_mime_type: BTreeMap<String, usize>,
+    // Size of the binary files, Workspace exports are not known upfront
+    pub total_bytes: u64,
+    pub workspace_exports: usize,
+    pub cached_files: usize,
+    pub estimated_api_calls: usize,
+}
+
+impl Preview {
+    // Same ETag as the download of the previews would get, known before anything is downloaded
+    pub fn get_etag(previews: &[Preview], options: &DownloadOptions) -> String {
+        let mut version_keys = Vec::new();
+        for preview in previews {
+            preview.tree.collect_version_keys(&mut version_keys);
+        }
+        get_output_etag(version_keys, options)
+    }
+}
+
+impl PreviewNode {
+    fn collect_version_keys(&self, version_keys: &mut Vec<String>) {
+        version_keys.extend(self.outputs.iter().map(|output| output.version_key.clone()));
+        for child in self.children.iter() {
+            child.collect_version_keys(version_keys);
+        }
+    }
+}
+
+// Weak ETag describing which versions went into the output and how they were processed
+// Hashed with sha256 so that it stays the same across builds and deploys
+pub fn get_output_etag(mut version_keys: Vec<String>, options: &DownloadOptions) -> String {
+    version_keys.sort();
+
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{:?}", options));
+    for version_key in version_keys {
+        hasher.update([0]);
+        hasher.update(version_key);
+    }
+    format!("W/\"{:x}\"", hasher.finalize())
+}
+
+// Server wide settings of the resumable uploads
+#[derive(Clone, Debug)]
+pub struct UploadOptions {
+    // Files of at least this many bytes are uploaded resumably by /upload
+    pub resumable_threshold: u64,
+    pub chunk_size: u64,
+    // Attempts per chunk before the upload gives up, the session is kept to resume it later
+    pub max_chunk_attempts: u32,
+}
+
+impl UploadOptions {
+    // Configured through RESUMABLE_UPLOAD_THRESHOLD, UPLOAD_CHUNK_SIZE and UPLOAD_CHUNK_ATTEMPTS
+    pub fn from_env() -> Result<Self> {
+        let parse = |env_name: &str| match env::var(env_name) {
+            std::result::Result::Ok(value) => value
+                .parse::<u64>()
+                .map(Some)
+                .map_err(|e| anyhow!("Invalid {}: {}", env_name, e)),
+            Err(_) => Ok(None),
+        };
+
+        let upload_options = Self {
+            resumable_threshold: parse("RESUMABLE_UPLOAD_THRESHOLD")?
+                .unwrap_or(DEFAULT_RESUMABLE_UPLOAD_THRESHOLD),
+            chunk_size: parse("UPLOAD_CHUNK_SIZE")?.unwrap_or(DEFAULT_UPLOAD_CHUNK_SIZE),
+            max_chunk_attempts: parse("UPLOAD_CHUNK_ATTEMPTS")?
+                .map(|max_chunk_attempts| max_chunk_attempts as u32)
+                .unwrap_or(DEFAULT_UPLOAD_CHUNK_ATTEMPTS),
+        };
+        if upload_options.chunk_size == 0
+            || !upload_options
+                .chunk_size
+                .is_multiple_of(UPLOAD_CHUNK_ALIGNMENT)
+        {
+            bail!(
+                "Invalid UPLOAD_CHUNK_SIZE: has to be a multiple of {} bytes",
+                UPLOAD_CHUNK_ALIGNMENT
+            );
+        }
+        if upload_options.max_chunk_attempts == 0 {
+            bail!("Invalid UPLOAD_CHUNK_ATTEMPTS: has to be at least 1");
+        }
+        Ok(upload_options)
+    }
+}
+
+// Server wide limits above which downloads are refused
+#[derive(Clone, Debug)]
+pub struct DownloadLimits {
+    pub max_files: Option<usize>,
+    pub max_bytes: Option<u64>,
+    // Folders nested deeper are left out and reported, shortcuts can otherwise nest folders endlessly
+    pub max_depth: usize,
+}
+
+impl DownloadLimits {
+    // Configured through MAX_DOWNLOAD_FILES and MAX_DOWNLOAD_BYTES, unlimited otherwise
+    // MAX_FOLDER_DEPTH defaults to DEFAULT_MAX_FOLDER_DEPTH
+    pub fn from_env() -> Result<Self> {
+        let parse = |env_name: &str| match env::var(env_name) {
+            std::result::Result::Ok(value) => value
+                .parse::<u64>()
+                .map(Some)
+                .map_err(|e| anyhow!("Invalid {}: {}", env_name, e)),
+            Err(_) => Ok(None),
+        };
+
+        Ok(Self {
+            max_files: parse("MAX_DOWNLOAD_FILES")?.map(|max_files| max_files as usize),
+            max_bytes: parse("MAX_DOWNLOAD_BYTES")?,
+            max_depth: parse("MAX_FOLDER_DEPTH")?
+                .map(|max_depth| max_depth as usize)
+                .unwrap_or(DEFAULT_MAX_FOLDER_DEPTH),
+        })
+    }
+
+    // Whether downloads have to be previewed before they start
+    pub fn is_enabled(&self) -> bool {
+        self.max_files.is_some() || self.max_bytes.is_some()
+    }
+
+    // Checks everything the previews would download together
+    pub fn check(&self, previews: &[Preview]) -> Result<()> {
+        let file_count = previews
+            .iter()
+            .map(|preview| preview.file_count)
+            .sum::<usize>();
+        let total_bytes = previews
+            .iter()
+            .map(|preview| preview.total_bytes)
+            .sum::<u64>();
+
+        if let Some(max_files) = self.max_files {
+            if file_count > max_files {
+                bail!(
+                    "Download contains {} files, more than the limit of {}",
+                    file_count,
+                    max_files
+                );
+            }
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            if total_bytes > max_bytes {
+                bail!(
+                    "Download contains {} bytes, more than the limit of {}",
+                    total_bytes,
+                    max_bytes
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+// One of the links bundled into a single output
+#[derive(Clone, Debug, Deserialize)]
+pub struct DownloadLink {
+    pub link: String,
+    // Subfolder of the output the link is placed in
+    // Defaults to the name of linked folders and to the root for files
+    pub folder: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkStatus {
+    Accessible,
+    NotFound,
+    PermissionDenied,
+    // Not a Drive link at all
+    InvalidLink,
+    // Drive failed for another reason or kept refusing because of rate limits
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkVisibility {
+    // Anyone with the link can open it
+    Public,
+    // Only shared with specific accounts, the one of the server included
+    Restricted,
+}
+
+// Result of checking a link without downloading it
+#[derive(Clone, Debug, Serialize)]
+pub struct LinkCheck {
+    pub link: String,
+    pub status: LinkStatus,
+    pub file_id: Option<String>,
+    pub name: Option<String>,
+    pub mime_type: Option<String>,
+    pub is_folder: bool,
+    pub is_workspace_file: bool,
+    // Google Workspace files and folders have no size
+    pub size: Option<i64>,
+    // Unknown when the server is not allowed to see the permissions of the file
+    pub visibility: Option<LinkVisibility>,
+    pub error: Option<String>,
+}
+
+impl LinkCheck {
+    pub fn new(link: String, status: LinkStatus) -> Self {
+        Self {
+            link,
+            status,
+            file_id: None,
+            name: None,
+            mime_type: None,
+            is_folder: false,
+            is_workspace_file: false,
+            size: None,
+            visibility: None,
+            error: None,
+        }
+    }
+}
+
+// Row of a roster such as a roll number along with the submitted link
+#[derive(Clone, Debug)]
+pub struct RosterRow {
+    // Position of the row in the roster, starting from 1
+    pub row: usize,
+    pub link: String,
+    pub columns: HashMap<String, String>,
+}
+
+// Names roster entries after the columns of their row, eg. `{roll}_{name}.{ext}`
+// `{name}` and `{ext}` come from the downloaded file and `{row}` is the position of the row
+// Columns of the roster take precedence over these when they share a name
+#[derive(Clone, Debug)]
+pub struct NamingTemplate(String);
+
+impl NamingTemplate {
+    pub fn new(template: String, columns: &[String]) -> Result<Self> {
+        if template.trim().is_empty() {
+            bail!("Naming template cannot be empty");
+        }
+
+        for placeholder in Self::get_placeholders(template.as_str())? {
+            let is_known = ["name", "ext", "row"].contains(&placeholder)
+                || columns.iter().any(|column| column == placeholder);
+            if !is_known {
+                bail!("Naming template uses unknown column `{}`", placeholder);
+            }
+        }
+        Ok(Self(template))
+    }
+
+    fn get_placeholders(template: &str) -> Result<Vec<&str>> {
+        let mut placeholders = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("Naming template has an unclosed `{{`"))?;
+            placeholders.push(&rest[start + 1..start + end]);
+            rest = &rest[start + end + 1..];
+        }
+        Ok(placeholders)
+    }
+
+    // Folders have no extension so the separator before `{ext}` is dropped for them
+    // Every placeholder is replaced in a single pass so that values are never expanded again
+    pub fn render(&self, row: &RosterRow, name: &str, ext: &str) -> String {
+        let row_number = row.row.to_string();
+        let mut rendered = String::new();
+        let mut rest = self.0.as_str();
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}') else {
+                break;
+            };
+            let placeholder = &rest[start + 1..start + end];
+            rendered.push_str(&rest[..start]);
+            // Columns named like the built in placeholders win over them
+            let value = match row.columns.get(placeholder) {
+                Some(value) => value.as_str(),
+                None => match placeholder {
+                    "name" => name,
+                    "ext" => ext,
+                    "row" => row_number.as_str(),
+                    _ => "",
+                },
+            };
+            rendered.push_str(value);
+            rest = &rest[start + end + 1..];
+        }
+        rendered.push_str(rest);
+
+        // Blank columns or values like `..` render to nothing, the entry is named after its row then
+        let rendered = Self::sanitize(rendered.as_str());
+        if !rendered.is_empty() {
+            return rendered;
+        }
+        let fallback = match ext {
+            "" => format!("{}_{}", row_number, name),
+            _ => format!("{}_{}.{}", row_number, name, ext),
+        };
+        Self::sanitize(fallback.as_str())
+    }
+
+    // Values must not be able to nest entries into other folders
+    fn sanitize(rendered: &str) -> String {
+        rendered
+            .replace('/', "_")
+            .trim_end_matches('.')
+            .trim()
+            .to_string()
+    }
+}
+
+// State shared by every task of a single download request
+pub struct DownloadSession {
+    pub options: DownloadOptions,
+    // The file or folder the download link points at
+    pub root: DriveFile,
+    // Where the contents of the linked folder are placed inside the output
+    pub root_path: String,
+    pub output_path: String,
+    // Where the files are written to on disk
+    pub base_path: String,
+    pub downloaded_files: Mutex<Vec<FileManager>>,
+    pub errors: Mutex<Vec<DownloadError>>,
+    // Every file the download came across, changed or not, to tell which ones were deleted
+    pub seen_file_ids: Mutex<HashSet<String>>,
+    // Folders whose contents were not walked, an empty path stands for the whole output
+    pub skipped_folder_paths: Mutex<Vec<String>>,
+    // Files by content key, held while the content is downloaded so that it is only downloaded once
+    pub content_downloads: Mutex<HashMap<String, Arc<AsyncMutex<Option<FileManager>>>>>,
+}
+
+impl DownloadSession {
+    pub fn new(options: DownloadOptions, root: DriveFile) -> Self {
+        Self {
+            output_path: options.get_output_path(),
+            root_path: String::new(),
+            base_path: TMP_FILES_UNCOMPRESSED_BASE_PATH.to_string(),
+            options,
+            root,
+            downloaded_files: Mutex::new(Vec::new()),
+            errors: Mutex::new(Vec::new()),
+            seen_file_ids: Mutex::new(HashSet::new()),
+            skipped_folder_paths: Mutex::new(Vec::new()),
+            content_downloads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Lock of the content of the file, the file it was first downloaded as is left inside once done
+    pub fn get_content_lock(
+        &self,
+        file_manager: &FileManager,
+    ) -> Option<Arc<AsyncMutex<Option<FileManager>>>> {
+        let content_key = file_manager.get_content_key()?;
+        Some(
+            self.content_downloads
+                .lock()
+                .unwrap()
+                .entry(content_key)
+                .or_default()
+                .clone(),
+        )
+    }
+
+    // Files of earlier downloads inside the folder are not reported as deletions
+    pub fn skip_folder(&self, folder_path: String) {
+        self.skipped_folder_paths.lock().unwrap().push(folder_path);
+    }
+
+    pub fn report_error(&self, file: &DriveFile, error: String) {
+        let name = file.name.clone().unwrap_or_default();
+        println!("{} | {}", name, error);
+        self.errors.lock().unwrap().push(DownloadError {
+            file_id: file.id.clone().unwrap_or_default(),
+            name,
+            error,
+            row: None,
+        });
+    }
+
+    // Attachment name of the output based on the downloaded file or folder
+    pub fn get_output_name(&self) -> String {
+        let name = self.root.name.clone().unwrap_or("output".to_string());
+        // Only files stored on Drive carry an extension worth replacing
+        let stem = match self.root.file_extension.as_ref() {
+            Some(ext) if !ext.is_empty() => name
+                .strip_suffix(format!(".{}", ext).as_str())
+                .unwrap_or(name.as_str())
+                .to_string(),
+            _ => name.clone(),
+        };
+        format!("{}.{}", stem, self.options.get_output_ext())
+    }
+
+    // Removes the output along with the parts a split archive is written from
+    pub fn remove_output(&self) {
+        let _ = std::fs::remove_file(self.output_path.as_str());
+        if let std::result::Result::Ok(part_paths) = glob::glob(&format!(
+            "{}.part*",
+            Pattern::escape(self.output_path.as_str())
+        )) {
+            for part_path in part_paths.flatten() {
+                let _ = std::fs::remove_file(part_path);
+            }
+        }
+    }
+
+    pub fn get_etag(&self) -> String {
+        let version_keys = self
+            .downloaded_files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|file| file.get_version_key())
+            .collect();
+        get_output_etag(version_keys, &self.options)
+    }
 }
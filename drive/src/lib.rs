@@ -1,31 +1,55 @@
 extern crate google_drive3 as drive;
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
 
-use anyhow::{Error, Ok, Result};
+use anyhow::{anyhow, bail, Error, Ok, Result};
 use drive::{
-    api::{File, FileList},
+    api::{File, FileList, Revision},
     hyper,
     hyper_rustls::{self, HttpsConnector},
     oauth2::authenticator::Authenticator,
     DriveHub,
 };
-use fs::{archive::archive_v2, cache::CacheManager, FileManager};
-use futures::future::join_all;
-use interface::CreateFileStruct;
-use tokio::spawn;
+use fs::{
+    archive::{archive_v2, ArchiveReports},
+    cache::CacheManager,
+    interface::{ExportFormats, OutputMode},
+    merge::merge_pdf,
+    FileManager,
+};
+use interface::{
+    CreateFileStruct, DownloadLimits, DownloadLink, DownloadOptions, DownloadSession, LinkCheck,
+    NamingTemplate, Preview, RosterRow, SingleFile, StreamSource, UploadBatch, UploadOptions,
+};
+use tokio::{spawn, sync::Mutex as AsyncMutex};
 use upload::upload_batch;
 
+pub mod check;
 pub mod create;
 pub mod download;
 pub mod interface;
 pub mod link;
 pub mod list;
+pub mod preview;
+pub mod revisions;
+pub mod roster;
+pub mod stream;
 pub mod upload;
 
 #[derive(Clone)]
 pub struct DriveManager {
     pub hub: Arc<DriveHub<HttpsConnector<drive::hyper::client::HttpConnector>>>,
     pub cache: Arc<Mutex<CacheManager>>,
+    // Server wide export formats used unless a request asks for others
+    pub export_formats: ExportFormats,
+    pub limits: DownloadLimits,
+    pub uploads: UploadOptions,
+    // Held while a resumable session is in use so that identical uploads never share one
+    pub upload_sessions: Arc<Mutex<HashMap<String, Arc<AsyncMutex<()>>>>>,
+    // Files being fetched into the cache after they were streamed in parts
+    pub stream_fills: Arc<Mutex<HashSet<String>>>,
 }
 
 impl DriveManager {
@@ -46,6 +70,11 @@ impl DriveManager {
         Ok(Self {
             hub,
             cache: Arc::new(Mutex::new(CacheManager::new())),
+            export_formats: ExportFormats::from_env()?,
+            limits: DownloadLimits::from_env()?,
+            uploads: UploadOptions::from_env()?,
+            upload_sessions: Arc::new(Mutex::new(HashMap::new())),
+            stream_fills: Arc::new(Mutex::new(HashSet::new())),
         })
     }
 
@@ -65,7 +94,7 @@ impl DriveManager {
     pub async fn upload_files(
         &self,
         upload_files_req: Vec<CreateFileStruct>,
-    ) -> Result<Vec<Option<String>>> {
+    ) -> Result<UploadBatch> {
         upload_batch(Arc::new(self.clone()), upload_files_req).await
     }
 
@@ -82,17 +111,145 @@ impl DriveManager {
         )
     }
 
-    pub async fn download_file(&self, url: &str) -> Result<Vec<FileManager>> {
-        let response = download::universal(Arc::new(self.clone()), url)
-            .await
-            .unwrap();
-        let downloaded_files = response.lock().unwrap().clone();
-        archive_v2(downloaded_files.clone()).await;
+    pub async fn download_file(
+        &self,
+        url: &str,
+        options: DownloadOptions,
+    ) -> Result<Arc<DownloadSession>> {
+        let session = download::universal(Arc::new(self.clone()), url, options).await?;
+        self.write_output(session).await
+    }
+
+    // Bundles every link into a single output
+    pub async fn download_files(
+        &self,
+        links: Vec<DownloadLink>,
+        options: DownloadOptions,
+    ) -> Result<Arc<DownloadSession>> {
+        let session = download::multiple(Arc::new(self.clone()), links, options).await?;
+        self.write_output(session).await
+    }
+
+    // Downloads the link of every roster row and names the entries after the row
+    pub async fn download_roster(
+        &self,
+        rows: Vec<RosterRow>,
+        template: NamingTemplate,
+        options: DownloadOptions,
+    ) -> Result<Arc<DownloadSession>> {
+        let session = roster::roster(Arc::new(self.clone()), rows, template, options).await?;
+        self.write_output(session).await
+    }
+
+    async fn write_output(&self, session: Arc<DownloadSession>) -> Result<Arc<DownloadSession>> {
+        let options = session.options.clone();
+        let downloaded_files = session.downloaded_files.lock().unwrap().clone();
+        let mut reports = ArchiveReports {
+            errors: session.errors.lock().unwrap().clone(),
+            ..Default::default()
+        };
+        // Deletions can only be told apart from unchanged files with an earlier manifest
+        if !options.incremental.manifest.is_empty() {
+            let seen_file_ids = session.seen_file_ids.lock().unwrap();
+            let skipped_folder_paths = session.skipped_folder_paths.lock().unwrap();
+            reports.deletions = Some(
+                options
+                    .incremental
+                    .get_deletions(&seen_file_ids, &skipped_folder_paths),
+            );
+            reports.unchanged = options
+                .incremental
+                .get_unchanged(&seen_file_ids, &downloaded_files);
+        }
+        let output = match options.output {
+            OutputMode::Archive => {
+                archive_v2(
+                    downloaded_files.clone(),
+                    reports,
+                    options.archive,
+                    session.output_path.as_str(),
+                )
+                .await
+            }
+            OutputMode::Pdf => {
+                merge_pdf(
+                    downloaded_files.clone(),
+                    options.merge,
+                    session.output_path.as_str(),
+                )
+                .await
+            }
+        };
         spawn(CacheManager::cleanup_and_store_in_cache(
-            downloaded_files.clone(),
+            downloaded_files,
             self.cache.clone(),
         ));
-        Ok(downloaded_files)
+        // A half written output is never served and would stay behind in tmp/
+        if output.is_err() {
+            session.remove_output();
+        }
+        output?;
+        Ok(session)
+    }
+
+    // Reports whether every link can be downloaded without downloading anything
+    pub async fn check_links(&self, links: Vec<String>) -> Vec<LinkCheck> {
+        check::check_links(Arc::new(self.clone()), links).await
+    }
+
+    pub async fn list_revisions(&self, file_id: &str) -> Result<Vec<Revision>> {
+        revisions::list_revisions(Arc::new(self.clone()), file_id).await
+    }
+
+    pub async fn preview(&self, url: &str, options: DownloadOptions) -> Result<Preview> {
+        preview::preview(Arc::new(self.clone()), url, options).await
+    }
+
+    // What a single file download would serve, known from the metadata alone
+    pub async fn describe_single_file(
+        &self,
+        file_id: &str,
+        options: &DownloadOptions,
+    ) -> Result<FileManager> {
+        download::describe_single(Arc::new(self.clone()), file_id, options).await
+    }
+
+    // Downloads a single file into the cache and returns where it can be served from
+    pub async fn download_single_file(
+        &self,
+        file_id: &str,
+        options: DownloadOptions,
+    ) -> Result<SingleFile> {
+        let session = download::single(Arc::new(self.clone()), file_id, options).await?;
+        if let Some(error) = session.errors.lock().unwrap().first() {
+            bail!("{}", error.error);
+        }
+        let file_manager = session
+            .downloaded_files
+            .lock()
+            .unwrap()
+            .first()
+            .cloned()
+            .ok_or_else(|| anyhow!("File is not available in the requested format"))?;
+
+        // Placeholders never enter the cache so they are served from where they were written
+        if file_manager.is_cached || file_manager.is_placeholder {
+            return Ok(SingleFile {
+                path: file_manager.get_optimal_target_path(),
+                file_manager,
+            });
+        }
+
+        CacheManager::cleanup_and_store_in_cache(vec![file_manager.clone()], self.cache.clone())
+            .await;
+        Ok(SingleFile {
+            path: CacheManager::get_cache_file_path(file_manager.clone()),
+            file_manager,
+        })
+    }
+
+    pub async fn stream_file(&self, file_id: &str, range: Option<&str>) -> Result<StreamSource> {
+        stream::stream(Arc::new(self.clone()), file_id, range).await
     }
 
     pub fn get_call_hash(
@@ -103,10 +260,7 @@ impl DriveManager {
     ) -> String {
         format!(
             "filesSTiK | {} | {} | {} | {}",
-            call_type.to_string(),
-            query,
-            page_token,
-            custom_fields
+            call_type, query, page_token, custom_fields
         )
     }
 }
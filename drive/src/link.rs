@@ -11,8 +11,7 @@ impl Link {
         let parsed_url = Url::parse(url.as_str());
         let mut id = url.clone();
 
-        if parsed_url.is_ok() {
-            let unwrapped_parsed_url = parsed_url.unwrap();
+        if let Ok(unwrapped_parsed_url) = parsed_url {
             let path_split = unwrapped_parsed_url
                 .path_segments()
                 .map(|c| c.collect::<Vec<_>>())
@@ -20,6 +19,12 @@ impl Link {
 
             if path_split.len() >= 3 {
                 id = path_split.get(2).unwrap().to_string();
+            } else if let Some((_, query_id)) = unwrapped_parsed_url
+                .query_pairs()
+                .find(|(key, _)| key == "id")
+            {
+                // Older share links look like `/open?id=...`
+                id = query_id.to_string();
             }
         }
 
@@ -28,4 +33,18 @@ impl Link {
             id,
         }
     }
+
+    // Only links to Drive or Docs with an ID in them can be downloaded
+    pub fn is_drive_link(&self) -> bool {
+        let parsed_url = match Url::parse(self.url.as_str()) {
+            Ok(parsed_url) => parsed_url,
+            Err(_) => return false,
+        };
+
+        let is_drive_host = matches!(
+            parsed_url.host_str(),
+            Some("drive.google.com") | Some("docs.google.com")
+        );
+        is_drive_host && !self.id.is_empty() && self.id != self.url
+    }
 }
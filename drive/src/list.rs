@@ -1,10 +1,10 @@
 use std::sync::Arc;
 
-use anyhow::{Error, Ok, Result};
+use anyhow::{anyhow, Error, Ok, Result};
 use drive::api::FileList;
 use fs::cache::RedisRequest;
 
-use crate::DriveManager;
+use crate::{download::DRIVE_CALL_CACHE_TTL_SECS, DriveManager};
 
 pub async fn get_file_list(
     drive: Arc<DriveManager>,
@@ -16,7 +16,7 @@ pub async fn get_file_list(
         query.unwrap_or_default(),
         page_token.unwrap_or_default(),
         custom_fields.unwrap_or(
-            "files/shortcutDetails, files/mimeType, files/name, files/id, files/fileExtension, files/headRevisionId, files/webViewLink, nextPageToken",
+            "files/shortcutDetails, files/mimeType, files/name, files/id, files/fileExtension, files/headRevisionId, files/webViewLink, files/md5Checksum, files/size, files/modifiedTime, files/version, files/description, files/properties, files/owners(displayName, emailAddress), nextPageToken",
         ),
     );
 
@@ -29,8 +29,8 @@ pub async fn get_file_list(
         .unwrap()
         .get_from_redis::<RedisRequest<FileList>>(cache_key.clone());
 
-    if redis_response.is_ok() {
-        let file_list = redis_response.unwrap().data;
+    if let std::result::Result::Ok(redis_response) = redis_response {
+        let file_list = redis_response.data;
         return Ok(file_list);
     }
 
@@ -45,13 +45,14 @@ pub async fn get_file_list(
         .supports_all_drives(true)
         .doit()
         .await
-        .expect("Error in fetching files");
+        .map_err(|e| anyhow!(e.to_string()))?;
 
-    drive.cache.lock().unwrap().set_to_redis(
+    drive.cache.lock().unwrap().set_to_redis_with_ttl(
         cache_key.clone(),
         RedisRequest {
             data: file_list.clone(),
         },
+        DRIVE_CALL_CACHE_TTL_SECS,
     );
 
     Ok(file_list)
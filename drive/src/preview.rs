@@ -0,0 +1,299 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{Ok, Result};
+use async_recursion::async_recursion;
+use fs::FileManager;
+use futures::future::join_all;
+use google_drive3::api::File;
+
+use crate::{
+    download::{get_shortcut_link_file, get_shortcut_target_id, join_folder_path, metadata},
+    interface::{
+        DownloadFilters, DownloadOptions, Preview, PreviewNode, PreviewOutput, ShortcutPolicy,
+    },
+    link::Link,
+    list::get_file_list,
+    DriveManager,
+};
+
+// Walks the tree the same way a download does while only ever asking for metadata
+struct PreviewWalk {
+    options: DownloadOptions,
+    api_calls: AtomicUsize,
+}
+
+// Files the Drive file ends up as inside the output
+fn get_outputs(
+    drive: &DriveManager,
+    file: &File,
+    folder_path: &str,
+    walk: &PreviewWalk,
+) -> Vec<PreviewOutput> {
+    let mime_type = file.mime_type.clone().unwrap_or_default();
+    let mut export_formats = walk
+        .options
+        .export_formats
+        .get(mime_type.as_str())
+        .iter()
+        .map(|format| Some(*format))
+        .collect::<Vec<_>>();
+    if export_formats.is_empty() {
+        export_formats.push(None);
+    }
+
+    export_formats
+        .into_iter()
+        .map(|export_format| {
+            FileManager::new(
+                file.clone(),
+                drive.cache.clone(),
+                "tmp/files".to_string(),
+                folder_path.to_string(),
+                export_format,
+                walk.options.processing.clone(),
+            )
+        })
+        .filter(|file_manager| walk.options.includes(file_manager))
+        .map(|file_manager| PreviewOutput {
+            path: file_manager.get_relative_path(),
+            mime_type: file_manager.mime_type.clone(),
+            is_cached: file_manager.is_cached,
+            is_placeholder: file_manager.is_placeholder,
+            version_key: file_manager.get_version_key(),
+        })
+        .collect()
+}
+
+#[async_recursion]
+async fn preview_folder(
+    drive: Arc<DriveManager>,
+    folder_id: String,
+    folder_path: String,
+    ancestor_ids: Vec<String>,
+    walk: Arc<PreviewWalk>,
+) -> Result<Vec<PreviewNode>> {
+    let filter = format!(
+        "'{}' in parents and trashed=false{}",
+        folder_id,
+        walk.options.filters.get_query()
+    );
+    let mut children = Vec::new();
+    let mut page_token = String::new();
+
+    loop {
+        walk.api_calls.fetch_add(1, Ordering::Relaxed);
+        let file_list = get_file_list(
+            drive.clone(),
+            Some(filter.as_str()),
+            Some(page_token.as_str()),
+            None,
+        )
+        .await?;
+
+        let nodes = join_all(file_list.files.unwrap_or_default().into_iter().map(|f| {
+            preview_file(
+                drive.clone(),
+                f,
+                folder_path.clone(),
+                ancestor_ids.clone(),
+                walk.clone(),
+            )
+        }))
+        .await;
+        for node in nodes {
+            children.extend(node?);
+        }
+
+        match file_list.next_page_token {
+            Some(next_page_token) => page_token = next_page_token,
+            None => break,
+        }
+    }
+
+    Ok(children)
+}
+
+#[async_recursion]
+async fn preview_file(
+    drive: Arc<DriveManager>,
+    file: File,
+    folder_path: String,
+    ancestor_ids: Vec<String>,
+    walk: Arc<PreviewWalk>,
+) -> Result<Option<PreviewNode>> {
+    let name = file.name.clone().unwrap_or_default();
+    let mime_type = file.mime_type.clone().unwrap_or_default();
+
+    match mime_type.as_str() {
+        "application/vnd.google-apps.folder" => {
+            let folder_path = join_folder_path(folder_path, name.clone());
+            if !walk
+                .options
+                .filters
+                .includes_folder(&folder_path, "", &name)
+                || DownloadFilters::get_depth(&folder_path, "") > drive.limits.max_depth
+                || ancestor_ids.contains(&file.id.clone().unwrap_or_default())
+            {
+                return Ok(None);
+            }
+            let mut ancestor_ids = ancestor_ids;
+            ancestor_ids.push(file.id.clone().unwrap_or_default());
+
+            let children = preview_folder(
+                drive.clone(),
+                file.id.clone().unwrap_or_default(),
+                folder_path.clone(),
+                ancestor_ids,
+                walk,
+            )
+            .await?;
+            Ok(Some(PreviewNode {
+                name,
+                path: folder_path,
+                mime_type,
+                children,
+                ..Default::default()
+            }))
+        }
+        "application/vnd.google-apps.shortcut" => {
+            let target_id = match get_shortcut_target_id(&file) {
+                Some(target_id) => target_id,
+                None => return Ok(None),
+            };
+
+            match walk.options.shortcuts {
+                ShortcutPolicy::Skip => Ok(None),
+                ShortcutPolicy::Link => {
+                    let outputs = get_outputs(
+                        &drive,
+                        &get_shortcut_link_file(&file, target_id),
+                        &folder_path,
+                        &walk,
+                    );
+                    Ok(Some(PreviewNode {
+                        name,
+                        path: folder_path,
+                        mime_type,
+                        outputs,
+                        ..Default::default()
+                    }))
+                }
+                ShortcutPolicy::Follow => {
+                    walk.api_calls.fetch_add(1, Ordering::Relaxed);
+                    // Broken shortcuts end up in the errors of the download instead
+                    match metadata(drive.clone(), target_id.as_str(), None).await {
+                        std::result::Result::Ok(target) => {
+                            preview_file(drive, target, folder_path, ancestor_ids, walk).await
+                        }
+                        Err(_) => Ok(None),
+                    }
+                }
+            }
+        }
+        _ if !walk.options.filters.includes_file(&file, &folder_path) => Ok(None),
+        // Unchanged files are left out of incremental downloads
+        _ if !walk.options.incremental.has_changed(&file) => Ok(None),
+        _ => {
+            let outputs = get_outputs(&drive, &file, &folder_path, &walk);
+            if outputs.is_empty() {
+                return Ok(None);
+            }
+
+            // Every output which is not cached costs a files.get or files.export
+            walk.api_calls.fetch_add(
+                outputs
+                    .iter()
+                    .filter(|output| !output.is_cached && !output.is_placeholder)
+                    .count(),
+                Ordering::Relaxed,
+            );
+            Ok(Some(PreviewNode {
+                name,
+                path: folder_path,
+                mime_type,
+                size: file.size,
+                outputs,
+                ..Default::default()
+            }))
+        }
+    }
+}
+
+// Totals over every file inside the tree
+fn summarize(node: &PreviewNode, preview: &mut Preview) {
+    if !node.outputs.is_empty() {
+        preview.file_count += node.outputs.len();
+        *preview
+            .files_by_mime_type
+            .entry(node.mime_type.clone())
+            .or_default() += 1;
+        preview.total_bytes += node.size.unwrap_or_default() as u64;
+        if node.mime_type.starts_with("application/vnd.google-apps") {
+            preview.workspace_exports += node
+                .outputs
+                .iter()
+                .filter(|output| !output.is_placeholder)
+                .count();
+        }
+        preview.cached_files += node
+            .outputs
+            .iter()
+            .filter(|output| output.is_cached)
+            .count();
+    }
+
+    for child in node.children.iter() {
+        summarize(child, preview);
+    }
+}
+
+pub async fn preview(
+    drive: Arc<DriveManager>,
+    url: &str,
+    options: DownloadOptions,
+) -> Result<Preview> {
+    let link = Link::new(url.to_string());
+    let walk = Arc::new(PreviewWalk {
+        options,
+        api_calls: AtomicUsize::new(1),
+    });
+
+    let root = metadata(drive.clone(), &link.id, None).await?;
+    // The contents of the linked folder are placed at the root of the output
+    let tree = if root.mime_type.clone().unwrap_or_default() == "application/vnd.google-apps.folder"
+    {
+        PreviewNode {
+            name: root.name.clone().unwrap_or_default(),
+            mime_type: root.mime_type.clone().unwrap_or_default(),
+            children: preview_folder(
+                drive.clone(),
+                root.id.clone().unwrap_or_default(),
+                String::new(),
+                vec![root.id.clone().unwrap_or_default()],
+                walk.clone(),
+            )
+            .await?,
+            ..Default::default()
+        }
+    } else {
+        preview_file(drive.clone(), root, String::new(), Vec::new(), walk.clone())
+            .await?
+            .unwrap_or_default()
+    };
+
+    let mut preview = Preview {
+        files_by_mime_type: BTreeMap::new(),
+        ..Default::default()
+    };
+    summarize(&tree, &mut preview);
+    preview.estimated_api_calls = walk.api_calls.load(Ordering::Relaxed);
+    preview.tree = tree;
+
+    Ok(preview)
+}
@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Ok, Result};
+use fs::cache::RedisRequest;
+use google_drive3::api::{File, Revision};
+
+use crate::{download::DRIVE_READONLY_SCOPE, DriveManager};
+
+static REVISION_FIELDS: &str = "id, mimeType, md5Checksum, size, modifiedTime, exportLinks, keepForever, originalFilename, lastModifyingUser(displayName, emailAddress)";
+
+// Revisions never change once created so their metadata is cached for good
+pub async fn revision_metadata(
+    drive: Arc<DriveManager>,
+    file_id: &str,
+    revision_id: &str,
+) -> Result<Revision> {
+    let cache_key = DriveManager::get_call_hash(
+        "revisions.get",
+        file_id.to_string(),
+        revision_id.to_string(),
+        REVISION_FIELDS.to_string(),
+    );
+    let redis_response = drive
+        .cache
+        .lock()
+        .unwrap()
+        .get_from_redis::<RedisRequest<Revision>>(cache_key.clone());
+
+    if let std::result::Result::Ok(redis_response) = redis_response {
+        return Ok(redis_response.data);
+    }
+
+    let (_, revision) = drive
+        .hub
+        .revisions()
+        .get(file_id, revision_id)
+        .add_scope(DRIVE_READONLY_SCOPE)
+        .param("fields", REVISION_FIELDS)
+        .doit()
+        .await
+        .map_err(|e| anyhow!(e.to_string()))?;
+
+    drive.cache.lock().unwrap().set_to_redis(
+        cache_key,
+        RedisRequest {
+            data: revision.clone(),
+        },
+    );
+
+    Ok(revision)
+}
+
+// Makes the file describe the requested revision so that it gets cached under that revision
+pub async fn apply_revision(
+    drive: Arc<DriveManager>,
+    file_metadata: File,
+    revision_id: &str,
+) -> Result<File> {
+    let file_id = file_metadata
+        .id
+        .clone()
+        .ok_or_else(|| anyhow!("File does not have an ID"))?;
+    let revision = revision_metadata(drive, file_id.as_str(), revision_id).await?;
+
+    Ok(File {
+        head_revision_id: revision.id,
+        md5_checksum: revision.md5_checksum,
+        size: revision.size,
+        modified_time: revision.modified_time,
+        mime_type: revision.mime_type.or(file_metadata.mime_type.clone()),
+        ..file_metadata
+    })
+}
+
+// Revisions of the file from the oldest to the newest
+// Drive may leave out revisions of Google Workspace files which were merged together
+pub async fn list_revisions(drive: Arc<DriveManager>, file_id: &str) -> Result<Vec<Revision>> {
+    let mut revisions = Vec::new();
+    let mut page_token: Option<String> = None;
+
+    loop {
+        let mut request = drive
+            .hub
+            .revisions()
+            .list(file_id)
+            .add_scope(DRIVE_READONLY_SCOPE)
+            .page_size(1000)
+            .param(
+                "fields",
+                format!("nextPageToken, revisions({})", REVISION_FIELDS).as_str(),
+            );
+        if let Some(page_token) = page_token.as_ref() {
+            request = request.page_token(page_token);
+        }
+        let (_, revision_list) = request.doit().await.map_err(|e| anyhow!(e.to_string()))?;
+
+        revisions.extend(revision_list.revisions.unwrap_or_default());
+        page_token = revision_list.next_page_token;
+        if page_token.is_none() {
+            break;
+        }
+    }
+
+    Ok(revisions)
+}
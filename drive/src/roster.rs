@@ -0,0 +1,171 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, bail, Ok, Result};
+use csv::ReaderBuilder;
+use fs::{interface::DownloadError, TMP_FILES_UNCOMPRESSED_BASE_PATH};
+use futures::future::join_all;
+use serde_json::Value;
+use tokio::spawn;
+
+use crate::{
+    download::{
+        get_api_error, is_rate_limit_error, join_folder_path, merge_sessions, metadata,
+        universal_into,
+    },
+    interface::{DownloadOptions, DownloadSession, NamingTemplate, RosterRow},
+    link::Link,
+    DriveManager,
+};
+
+// Column of the roster holding the Drive link of every row
+static LINK_COLUMN: &str = "link";
+
+// Reads a roster with a header row, eg. `roll,link`
+pub fn parse_csv(data: &[u8]) -> Result<(Vec<String>, Vec<RosterRow>)> {
+    let mut reader = ReaderBuilder::new().trim(csv::Trim::All).from_reader(data);
+    let headers = reader
+        .headers()?
+        .iter()
+        .map(|header| header.to_string())
+        .collect::<Vec<_>>();
+
+    let mut rows = Vec::new();
+    for (row_idx, record) in reader.records().enumerate() {
+        let columns = headers
+            .iter()
+            .cloned()
+            .zip(record?.iter().map(|value| value.to_string()))
+            .collect::<HashMap<_, _>>();
+        rows.push(get_row(row_idx + 1, columns)?);
+    }
+    Ok((headers, rows))
+}
+
+// Reads a roster given as an array of objects, eg. `[{"roll": "21CS001", "link": "..."}]`
+pub fn parse_json(data: &[u8]) -> Result<(Vec<String>, Vec<RosterRow>)> {
+    let values = serde_json::from_slice::<Vec<HashMap<String, Value>>>(data)?;
+
+    let mut headers = Vec::new();
+    let mut rows = Vec::new();
+    for (row_idx, value) in values.into_iter().enumerate() {
+        let columns = value
+            .into_iter()
+            .map(|(column, value)| match value {
+                Value::String(value) => (column, value),
+                Value::Null => (column, String::new()),
+                value => (column, value.to_string()),
+            })
+            .collect::<HashMap<_, _>>();
+        for column in columns.keys() {
+            if !headers.contains(column) {
+                headers.push(column.clone());
+            }
+        }
+        rows.push(get_row(row_idx + 1, columns)?);
+    }
+    Ok((headers, rows))
+}
+
+fn get_row(row: usize, columns: HashMap<String, String>) -> Result<RosterRow> {
+    let link = columns
+        .iter()
+        .find(|(column, _)| column.eq_ignore_ascii_case(LINK_COLUMN))
+        .map(|(_, link)| link.clone())
+        .ok_or_else(|| anyhow!("Row {} does not have a `{}` column", row, LINK_COLUMN))?;
+    Ok(RosterRow { row, link, columns })
+}
+
+async fn download_row(
+    drive: Arc<DriveManager>,
+    row: RosterRow,
+    template: NamingTemplate,
+    options: DownloadOptions,
+) -> Result<Arc<DownloadSession>> {
+    let link = Link::new(row.link.clone());
+    if !link.is_drive_link() {
+        bail!("Not a Google Drive link");
+    }
+
+    let file_metadata = match metadata(drive.clone(), &link.id, None).await {
+        std::result::Result::Ok(file_metadata) => file_metadata,
+        // Drive answers with 404 for files the account cannot see and 403 for files it cannot read
+        Err(e) if !is_rate_limit_error(&e) && matches!(get_api_error(&e), Some((403 | 404, _))) => {
+            bail!("Link is private or does not exist")
+        }
+        Err(e) => bail!("Link is broken: {}", e),
+    };
+
+    let base_path = format!("{}/roster/{}", TMP_FILES_UNCOMPRESSED_BASE_PATH, row.row);
+    let session = match file_metadata.mime_type.clone().unwrap_or_default().as_str() {
+        // Linked folders are named after the row and keep their contents as they are
+        "application/vnd.google-apps.folder" => {
+            let folder_name =
+                template.render(&row, file_metadata.name.unwrap_or_default().as_str(), "");
+            universal_into(
+                drive,
+                row.link.as_str(),
+                options,
+                Some(join_folder_path(String::new(), folder_name)),
+                base_path,
+            )
+            .await?
+        }
+        _ => {
+            let session =
+                universal_into(drive, row.link.as_str(), options, None, base_path).await?;
+            for file_manager in session.downloaded_files.lock().unwrap().iter_mut() {
+                let name = file_manager
+                    .file_name
+                    .strip_suffix(format!(".{}", file_manager.ext).as_str())
+                    .unwrap_or(file_manager.file_name.as_str())
+                    .to_string();
+                file_manager.renamed_path =
+                    Some(template.render(&row, name.as_str(), file_manager.ext.as_str()));
+            }
+            session
+        }
+    };
+
+    // Files of the row which could not be downloaded are reported against the row as well
+    for error in session.errors.lock().unwrap().iter_mut() {
+        error.row = Some(row.row);
+    }
+    Ok(session)
+}
+
+// Downloads the link of every row concurrently, rows which fail end up in the errors of the output
+pub async fn roster(
+    drive: Arc<DriveManager>,
+    rows: Vec<RosterRow>,
+    template: NamingTemplate,
+    options: DownloadOptions,
+) -> Result<Arc<DownloadSession>> {
+    if options.revision.is_some() {
+        bail!("Revisions can only be requested for a single link");
+    }
+
+    let mut thread_handlers = vec![];
+    for row in rows.iter() {
+        thread_handlers.push(spawn(download_row(
+            drive.clone(),
+            row.clone(),
+            template.clone(),
+            options.clone(),
+        )));
+    }
+
+    let mut row_sessions = vec![];
+    for (row, row_session) in rows.iter().zip(join_all(thread_handlers).await) {
+        row_sessions.push(row_session?.map_err(|e| {
+            println!("Row {} | {} | {}", row.row, row.link, e);
+            DownloadError {
+                file_id: String::new(),
+                name: row.link.clone(),
+                error: e.to_string(),
+                row: Some(row.row),
+            }
+        }));
+    }
+
+    Ok(merge_sessions("roster", options, row_sessions))
+}
@@ -0,0 +1,276 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Ok, Result};
+use chrono::Utc;
+use fs::{
+    cache::CacheManager, interface::ProcessingOptions, FileManager, TMP_FILES_STREAM_BASE_PATH,
+};
+use futures::{channel::mpsc, SinkExt};
+use google_drive3::{
+    api::File,
+    hyper::{
+        body::{Bytes, HttpBody},
+        header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, RANGE},
+        Body, Request, Response, StatusCode,
+    },
+};
+use tokio::{io::AsyncWriteExt, spawn};
+
+use crate::{
+    download::{resolve_file, DRIVE_READONLY_SCOPE},
+    interface::{DriveStream, SingleFile, StreamSource},
+    DriveManager,
+};
+
+static DRIVE_FILES_URL: &str = "https://www.googleapis.com/drive/v3/files";
+// Number of chunks buffered between Drive and the client
+static STREAM_BUFFER_SIZE: usize = 16;
+
+// Whether the response carries the whole file and not only a part of it
+// Ranges are never cached themselves, the whole file is fetched in the background instead
+fn covers_whole_file(status: u16, content_range: Option<&str>, size: Option<i64>) -> bool {
+    match (status, content_range, size) {
+        (200, _, _) => true,
+        (206, Some(content_range), Some(size)) => {
+            content_range == format!("bytes 0-{}/{}", size - 1, size)
+        }
+        _ => false,
+    }
+}
+
+// Partly written files under tmp/stream must not pile up when a stream ends early
+async fn remove_partial_file(file_manager: Option<FileManager>) {
+    if let Some(file_manager) = file_manager {
+        let _ = tokio::fs::remove_file(file_manager.get_target_path()).await;
+        if !file_manager.compressed_file_path.is_empty() {
+            let _ = tokio::fs::remove_file(file_manager.get_compressed_target_path()).await;
+        }
+    }
+}
+
+// Forwards the body to the client and, when it is the whole file, writes it to the cache as well
+async fn forward_body(
+    drive: Arc<DriveManager>,
+    mut body: Body,
+    mut sender: mpsc::Sender<std::io::Result<Bytes>>,
+    mut file_manager: Option<FileManager>,
+) {
+    let mut target_file = match file_manager.as_ref() {
+        Some(file_manager) => file_manager.create_target_file().await.ok(),
+        None => None,
+    };
+
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            std::result::Result::Ok(chunk) => chunk,
+            Err(e) => {
+                let _ = sender.send(Err(std::io::Error::other(e))).await;
+                remove_partial_file(file_manager).await;
+                return;
+            }
+        };
+
+        if let Some(file) = target_file.as_mut() {
+            if file.write_all(&chunk).await.is_err() {
+                target_file = None;
+            }
+        }
+
+        // The client went away, no need to read the rest
+        if sender.send(std::result::Result::Ok(chunk)).await.is_err() {
+            remove_partial_file(file_manager).await;
+            return;
+        }
+    }
+
+    let Some(mut file) = target_file else {
+        remove_partial_file(file_manager).await;
+        return;
+    };
+    let Some(mut file_manager) = file_manager.take() else {
+        return;
+    };
+    if file.flush().await.is_err() {
+        remove_partial_file(Some(file_manager)).await;
+        return;
+    }
+    match file_manager.finish_written_file().await {
+        std::result::Result::Ok(_) => {
+            CacheManager::cleanup_and_store_in_cache(vec![file_manager], drive.cache.clone()).await
+        }
+        Err(e) => {
+            println!(
+                "{} | Not caching streamed file: {e:?}",
+                file_manager.file_name
+            );
+            remove_partial_file(Some(file_manager)).await;
+        }
+    }
+}
+
+async fn send_media_request(
+    drive: Arc<DriveManager>,
+    file: &File,
+    range: Option<&str>,
+) -> Result<Response<Body>> {
+    let token = drive
+        .hub
+        .auth
+        .get_token(&[DRIVE_READONLY_SCOPE])
+        .await
+        .map_err(|e| anyhow!(e.to_string()))?;
+
+    let mut request = Request::get(format!(
+        "{}/{}?alt=media&supportsAllDrives=true&acknowledgeAbuse=true",
+        DRIVE_FILES_URL,
+        file.id.clone().unwrap()
+    ));
+    if let Some(token) = token.as_ref() {
+        request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    if let Some(range) = range {
+        request = request.header(RANGE, range);
+    }
+
+    Ok(drive
+        .hub
+        .client
+        .request(request.body(Body::empty())?)
+        .await?)
+}
+
+// Players read files in several ranges, none of which covers the whole file
+// The whole file is fetched into the cache once so that later reads are served from there
+async fn cache_in_background(drive: Arc<DriveManager>, file_manager: FileManager) {
+    let file_id = file_manager.file.id.clone().unwrap_or_default();
+    if !drive.stream_fills.lock().unwrap().insert(file_id.clone()) {
+        return;
+    }
+
+    let mut file_manager = file_manager;
+    let cached = match send_media_request(drive.clone(), &file_manager.file, None).await {
+        std::result::Result::Ok(response) if response.status().is_success() => {
+            match file_manager.write_body(response.into_body()).await {
+                std::result::Result::Ok(_) => file_manager.verify_written_file(),
+                Err(e) => Err(e),
+            }
+        }
+        std::result::Result::Ok(response) => {
+            Err(anyhow!("Drive responded with {}", response.status()))
+        }
+        Err(e) => Err(e),
+    };
+    match cached {
+        std::result::Result::Ok(_) => {
+            CacheManager::cleanup_and_store_in_cache(vec![file_manager], drive.cache.clone()).await
+        }
+        Err(e) => {
+            println!(
+                "{} | Not caching streamed file: {e:?}",
+                file_manager.file_name
+            );
+            remove_partial_file(Some(file_manager)).await;
+        }
+    }
+
+    drive.stream_fills.lock().unwrap().remove(&file_id);
+}
+
+async fn request_media(
+    drive: Arc<DriveManager>,
+    file_manager: FileManager,
+    range: Option<&str>,
+) -> Result<DriveStream> {
+    let file = file_manager.file.clone();
+    let response = send_media_request(drive.clone(), &file, range).await?;
+    let status = response.status().as_u16();
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string())
+    };
+    let content_length = header(CONTENT_LENGTH);
+    let content_range = header(CONTENT_RANGE);
+
+    // A range outside of the file is the mistake of the client, Drive tells it the actual size
+    if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        let (_, receiver) = mpsc::channel(0);
+        return Ok(DriveStream {
+            status,
+            mime_type: file.mime_type.clone().unwrap_or_default(),
+            content_length: None,
+            content_range,
+            body: receiver,
+        });
+    }
+    if !response.status().is_success() {
+        bail!("Drive responded with {}", response.status());
+    }
+
+    // Whole files are cached while they are forwarded, parts make the whole file get fetched
+    let file_manager = if covers_whole_file(status, content_range.as_deref(), file.size) {
+        Some(file_manager)
+    } else {
+        spawn(cache_in_background(drive.clone(), file_manager));
+        None
+    };
+
+    let (sender, receiver) = mpsc::channel(STREAM_BUFFER_SIZE);
+    spawn(forward_body(
+        drive.clone(),
+        response.into_body(),
+        sender,
+        file_manager,
+    ));
+
+    Ok(DriveStream {
+        status,
+        mime_type: file.mime_type.clone().unwrap_or_default(),
+        content_length,
+        content_range,
+        body: receiver,
+    })
+}
+
+// Streams a file straight from Drive unless it is already cached
+pub async fn stream(
+    drive: Arc<DriveManager>,
+    file_id: &str,
+    range: Option<&str>,
+) -> Result<StreamSource> {
+    let file = resolve_file(drive.clone(), file_id).await?;
+    if file
+        .mime_type
+        .clone()
+        .unwrap_or_default()
+        .starts_with("application/vnd.google-apps")
+    {
+        bail!("Only files stored on Drive can be streamed");
+    }
+
+    // Written to a directory of its own so that concurrent streams of the file do not collide
+    let file_manager = FileManager::new(
+        file,
+        drive.cache.clone(),
+        format!(
+            "{}/{}",
+            TMP_FILES_STREAM_BASE_PATH,
+            Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        ),
+        String::new(),
+        None,
+        ProcessingOptions::default(),
+    );
+    if file_manager.is_cached {
+        return Ok(StreamSource::Cached(Box::new(SingleFile {
+            path: file_manager.get_optimal_target_path(),
+            file_manager,
+        })));
+    }
+
+    Ok(StreamSource::Drive(
+        request_media(drive, file_manager, range).await?,
+    ))
+}
@@ -1,20 +1,434 @@
 use std::{
-    fs,
+    io::{self, Read, Seek, SeekFrom},
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use anyhow::{Ok, Result};
+use anyhow::{anyhow, bail, Ok, Result};
 use drive::api::{File, Permission};
+use fs::cache::RedisRequest;
 use futures::future::join_all;
-use tokio::spawn;
+use google_drive3::hyper::{
+    body::to_bytes,
+    header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LOCATION, RANGE},
+    Body, Method, Request, Response, StatusCode,
+};
+use md5::{Digest, Md5};
+use serde_json::json;
+use tokio::{spawn, task::spawn_blocking, time::sleep};
+
+use crate::{
+    interface::{CreateFileStruct, UploadBatch, UploadError},
+    DriveManager,
+};
+
+static DRIVE_UPLOAD_URL: &str = "https://www.googleapis.com/upload/drive/v3/files";
+static DRIVE_SCOPE: &str = "https://www.googleapis.com/auth/drive";
+static RESUMABLE_UPLOAD_FIELDS: &str = "webViewLink,id,md5Checksum";
+static CHUNK_RETRY_BACKOFF_MS: u64 = 500;
+
+enum UploadStatus {
+    // Number of bytes Drive has stored so far
+    Incomplete(u64),
+    Complete(Box<File>),
+    // Drive forgot the session, the upload has to start over
+    Expired,
+    // No status code when the request did not reach Drive at all
+    Failed(Option<StatusCode>, String),
+}
+
+impl UploadStatus {
+    fn is_retryable(&self) -> bool {
+        match self {
+            UploadStatus::Failed(None, _) => true,
+            UploadStatus::Failed(Some(status), _) => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
+}
+
+// The same file uploaded to the same place again picks up the session of the earlier attempt
+fn get_session_key(upload_file: &CreateFileStruct, size: u64, content_md5: &str) -> String {
+    DriveManager::get_call_hash(
+        "files.upload",
+        format!("{} | {}", upload_file.name, upload_file.parents.join(",")),
+        upload_file.file_id.clone().unwrap_or_default(),
+        format!("{} | {}", size, content_md5),
+    )
+}
+
+// Files are read on the blocking pool so that large uploads do not stall the runtime
+async fn get_content_md5(content: &std::fs::File) -> Result<String> {
+    let mut content = content.try_clone()?;
+    spawn_blocking(move || {
+        let mut hasher = Md5::new();
+        content.seek(SeekFrom::Start(0))?;
+        io::copy(&mut content, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await?
+}
+
+async fn read_chunk(content: &std::fs::File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let mut content = content.try_clone()?;
+    spawn_blocking(move || {
+        let mut chunk = vec![0; len as usize];
+        content.seek(SeekFrom::Start(offset))?;
+        content.read_exact(&mut chunk)?;
+        Ok(chunk)
+    })
+    .await?
+}
+
+fn get_content_range(offset: u64, len: u64, size: u64) -> String {
+    if len == 0 {
+        format!("bytes */{}", size)
+    } else {
+        format!("bytes {}-{}/{}", offset, offset + len - 1, size)
+    }
+}
 
-use crate::{interface::CreateFileStruct, DriveManager};
+async fn get_token(drive: Arc<DriveManager>) -> Result<Option<String>> {
+    drive
+        .hub
+        .auth
+        .get_token(&[DRIVE_SCOPE])
+        .await
+        .map_err(|e| anyhow!(e.to_string()))
+}
+
+// Creates the upload session, its URI is all that is needed to send the content
+async fn start_session(
+    drive: Arc<DriveManager>,
+    upload_file: &CreateFileStruct,
+    size: u64,
+) -> Result<String> {
+    let mime_type = upload_file
+        .mime_type
+        .as_ref()
+        .map(|mime_type| mime_type.to_string())
+        .unwrap_or(String::from("application/octet-stream"));
+    let (method, url, file_metadata) = match upload_file.file_id.as_ref() {
+        None => (
+            Method::POST,
+            format!(
+                "{}?uploadType=resumable&supportsAllDrives=true&ocrLanguage=en&fields={}",
+                DRIVE_UPLOAD_URL, RESUMABLE_UPLOAD_FIELDS
+            ),
+            json!({
+                "name": upload_file.name,
+                "mimeType": mime_type,
+                "parents": upload_file.parents,
+            }),
+        ),
+        // Parents cannot be changed through an update
+        Some(file_id) => (
+            Method::PATCH,
+            format!(
+                "{}/{}?uploadType=resumable&supportsAllDrives=true&ocrLanguage=en&newRevision=true&fields={}",
+                DRIVE_UPLOAD_URL, file_id, RESUMABLE_UPLOAD_FIELDS
+            ),
+            json!({
+                "name": upload_file.name,
+                "mimeType": mime_type,
+            }),
+        ),
+    };
+
+    let mut request = Request::builder()
+        .method(method)
+        .uri(url)
+        .header(CONTENT_TYPE, "application/json; charset=UTF-8")
+        .header("X-Upload-Content-Type", mime_type)
+        .header("X-Upload-Content-Length", size);
+    if let Some(token) = get_token(drive.clone()).await? {
+        request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let response = drive
+        .hub
+        .client
+        .request(request.body(Body::from(serde_json::to_vec(&file_metadata)?))?)
+        .await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = to_bytes(response.into_body()).await?;
+        bail!(
+            "Drive refused to start the upload with {}: {}",
+            status,
+            String::from_utf8_lossy(&body)
+        );
+    }
+    Ok(response
+        .headers()
+        .get(LOCATION)
+        .ok_or_else(|| anyhow!("Drive did not return an upload session"))?
+        .to_str()?
+        .to_string())
+}
+
+async fn read_status(response: Response<Body>) -> Result<UploadStatus> {
+    let status = response.status();
+    match status {
+        // Drive answers 308 as long as bytes are missing, Range lists the stored ones
+        StatusCode::PERMANENT_REDIRECT => {
+            let stored_bytes = match response.headers().get(RANGE) {
+                Some(range) => {
+                    let last_byte = range
+                        .to_str()?
+                        .rsplit('-')
+                        .next()
+                        .unwrap_or_default()
+                        .parse::<u64>()?;
+                    last_byte + 1
+                }
+                None => 0,
+            };
+            Ok(UploadStatus::Incomplete(stored_bytes))
+        }
+        StatusCode::OK | StatusCode::CREATED => {
+            let body = to_bytes(response.into_body()).await?;
+            Ok(UploadStatus::Complete(Box::new(serde_json::from_slice(
+                &body,
+            )?)))
+        }
+        StatusCode::NOT_FOUND | StatusCode::GONE => Ok(UploadStatus::Expired),
+        _ => {
+            let body = to_bytes(response.into_body()).await?;
+            Ok(UploadStatus::Failed(
+                Some(status),
+                String::from_utf8_lossy(&body).to_string(),
+            ))
+        }
+    }
+}
+
+// An empty chunk asks Drive how far the upload got
+async fn send_chunk(
+    drive: Arc<DriveManager>,
+    session_uri: &str,
+    content_range: String,
+    chunk: Vec<u8>,
+) -> Result<UploadStatus> {
+    let mut request = Request::put(session_uri)
+        .header(CONTENT_LENGTH, chunk.len())
+        .header(CONTENT_RANGE, content_range);
+    if let Some(token) = get_token(drive.clone()).await? {
+        request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+
+    match drive
+        .hub
+        .client
+        .request(request.body(Body::from(chunk))?)
+        .await
+    {
+        std::result::Result::Ok(response) => read_status(response).await,
+        Err(e) => Ok(UploadStatus::Failed(None, e.to_string())),
+    }
+}
+
+async fn query_status(
+    drive: Arc<DriveManager>,
+    session_uri: &str,
+    size: u64,
+) -> Result<UploadStatus> {
+    send_chunk(drive, session_uri, get_content_range(0, 0, size), vec![]).await
+}
+
+fn check_uploaded_file(file: Box<File>, content_md5: &str) -> Result<File> {
+    if let Some(md5_checksum) = file.md5_checksum.as_ref() {
+        if !content_md5.eq_ignore_ascii_case(md5_checksum) {
+            bail!(
+                "Drive stored md5 {} but {} was uploaded",
+                md5_checksum,
+                content_md5
+            );
+        }
+    }
+    Ok(*file)
+}
+
+// Uploads the content in chunks through Drive's resumable protocol
+// Chunks are retried on their own and, once the attempts run out, the session stays stored
+// so that uploading the same file again continues from the last byte Drive acknowledged
+pub async fn upload_resumable(
+    drive: Arc<DriveManager>,
+    upload_file: &CreateFileStruct,
+) -> Result<File> {
+    let size = upload_file.content.metadata()?.len();
+    let content_md5 = get_content_md5(&upload_file.content).await?;
+    let session_key = get_session_key(upload_file, size, content_md5.as_str());
+
+    // The same content uploaded to the same place at once waits for the earlier upload
+    let session_lock = drive
+        .upload_sessions
+        .lock()
+        .unwrap()
+        .entry(session_key.clone())
+        .or_default()
+        .clone();
+    let session_guard = session_lock.lock().await;
+    let uploaded_file = upload_to_session(
+        drive.clone(),
+        upload_file,
+        size,
+        content_md5,
+        session_key.clone(),
+    )
+    .await;
+    drop(session_guard);
+
+    // Nothing else waits for the session once only the map and this upload hold the lock
+    let mut upload_sessions = drive.upload_sessions.lock().unwrap();
+    if Arc::strong_count(&session_lock) == 2 {
+        upload_sessions.remove(&session_key);
+    }
+    uploaded_file
+}
+
+async fn upload_to_session(
+    drive: Arc<DriveManager>,
+    upload_file: &CreateFileStruct,
+    size: u64,
+    content_md5: String,
+    session_key: String,
+) -> Result<File> {
+    let chunk_size = drive.uploads.chunk_size;
+    let max_chunk_attempts = drive.uploads.max_chunk_attempts;
+
+    let stored_session_uri = drive
+        .cache
+        .lock()
+        .unwrap()
+        .get_from_redis::<RedisRequest<String>>(session_key.clone())
+        .ok()
+        .map(|redis_response| redis_response.data)
+        .filter(|session_uri| !session_uri.is_empty());
+
+    let mut offset = 0;
+    let mut session_uri = None;
+    if let Some(stored_session_uri) = stored_session_uri {
+        match query_status(drive.clone(), stored_session_uri.as_str(), size).await {
+            std::result::Result::Ok(UploadStatus::Incomplete(stored_bytes)) => {
+                println!(
+                    "{} | Resuming upload at byte {} of {}",
+                    upload_file.name, stored_bytes, size
+                );
+                offset = stored_bytes;
+                session_uri = Some(stored_session_uri);
+            }
+            std::result::Result::Ok(UploadStatus::Complete(file)) => {
+                drive.cache.lock().unwrap().delete_from_redis(session_key);
+                return check_uploaded_file(file, content_md5.as_str());
+            }
+            _ => drive
+                .cache
+                .lock()
+                .unwrap()
+                .delete_from_redis(session_key.clone()),
+        }
+    }
+    let mut session_uri = match session_uri {
+        Some(session_uri) => session_uri,
+        None => {
+            let session_uri = start_session(drive.clone(), upload_file, size).await?;
+            drive.cache.lock().unwrap().set_to_redis(
+                session_key.clone(),
+                RedisRequest {
+                    data: session_uri.clone(),
+                },
+            );
+            session_uri
+        }
+    };
+
+    let mut attempt = 1;
+    loop {
+        let chunk_len = chunk_size.min(size - offset);
+        let chunk = read_chunk(&upload_file.content, offset, chunk_len).await?;
+        let status = send_chunk(
+            drive.clone(),
+            session_uri.as_str(),
+            get_content_range(offset, chunk_len, size),
+            chunk,
+        )
+        .await?;
+
+        // A chunk Drive stored only part of is retried from where Drive stopped
+        let is_retryable = status.is_retryable() || matches!(status, UploadStatus::Incomplete(_));
+        match status {
+            UploadStatus::Incomplete(stored_bytes) if stored_bytes > offset => {
+                offset = stored_bytes;
+                attempt = 1;
+            }
+            UploadStatus::Complete(file) => {
+                drive.cache.lock().unwrap().delete_from_redis(session_key);
+                return check_uploaded_file(file, content_md5.as_str());
+            }
+            UploadStatus::Expired if attempt < max_chunk_attempts => {
+                println!("{} | Upload session expired, starting over", upload_file.name);
+                session_uri = start_session(drive.clone(), upload_file, size).await?;
+                drive.cache.lock().unwrap().set_to_redis(
+                    session_key.clone(),
+                    RedisRequest {
+                        data: session_uri.clone(),
+                    },
+                );
+                offset = 0;
+                attempt += 1;
+            }
+            _ if is_retryable && attempt < max_chunk_attempts => {
+                sleep(Duration::from_millis(
+                    CHUNK_RETRY_BACKOFF_MS * 2_u64.pow(attempt - 1),
+                ))
+                .await;
+                attempt += 1;
+                match query_status(drive.clone(), session_uri.as_str(), size).await? {
+                    UploadStatus::Incomplete(stored_bytes) => offset = stored_bytes,
+                    UploadStatus::Complete(file) => {
+                        drive.cache.lock().unwrap().delete_from_redis(session_key);
+                        return check_uploaded_file(file, content_md5.as_str());
+                    }
+                    _ => {}
+                }
+            }
+            // Retrying cannot help, neither can resuming the session later
+            UploadStatus::Failed(Some(status), message) if !is_retryable => {
+                drive.cache.lock().unwrap().delete_from_redis(session_key);
+                bail!("Drive refused the upload with {}: {}", status, message)
+            }
+            _ => bail!(
+                "Upload stopped at byte {} of {} after {} attempts, uploading the same file again resumes it",
+                offset,
+                size,
+                attempt
+            ),
+        }
+    }
+}
 
 pub async fn upload_file(
     drive: Arc<DriveManager>,
     upload_file: CreateFileStruct,
     link_store: Arc<Mutex<Vec<Option<String>>>>,
 ) -> Result<()> {
+    if upload_file.resumable {
+        println!("UPLOADING RESUMABLY");
+        match upload_resumable(drive.clone(), &upload_file).await {
+            std::result::Result::Ok(file) => {
+                return share_uploaded_file(drive, file, link_store).await
+            }
+            Err(e) => {
+                println!("{} | Upload failed: {e:?}", upload_file.name);
+                link_store.lock().unwrap().push(None);
+                return Err(e);
+            }
+        }
+    }
+
     let mime_type_string = format!("{}", upload_file.mime_type.clone().unwrap());
     let file = File {
         name: Some(upload_file.name.clone()),
@@ -50,6 +464,14 @@ pub async fn upload_file(
             .unwrap()
     };
 
+    share_uploaded_file(drive, file, link_store).await
+}
+
+async fn share_uploaded_file(
+    drive: Arc<DriveManager>,
+    file: File,
+    link_store: Arc<Mutex<Vec<Option<String>>>>,
+) -> Result<()> {
     // Create permissions for view access
     drive
         .hub
@@ -74,9 +496,13 @@ pub async fn upload_file(
 pub async fn upload_batch(
     drive: Arc<DriveManager>,
     upload_files: Vec<CreateFileStruct>,
-) -> Result<(Vec<Option<String>>)> {
+) -> Result<UploadBatch> {
     let mut thread_handlers = vec![];
     let link_store = Arc::new(Mutex::new(vec![]));
+    let names = upload_files
+        .iter()
+        .map(|upload_file| upload_file.name.clone())
+        .collect::<Vec<_>>();
 
     for file_metadata in upload_files {
         thread_handlers.push(spawn(upload_file(
@@ -86,9 +512,21 @@ pub async fn upload_batch(
         )))
     }
 
-    join_all(thread_handlers).await;
+    // Failed files are reported by name as their links are missing from the list
+    let mut errors = vec![];
+    for (name, uploaded) in names.into_iter().zip(join_all(thread_handlers).await) {
+        let error = match uploaded {
+            std::result::Result::Ok(std::result::Result::Ok(_)) => continue,
+            std::result::Result::Ok(Err(e)) => e.to_string(),
+            Err(e) => e.to_string(),
+        };
+        errors.push(UploadError { name, error });
+    }
 
     let link_store_populated = link_store.lock().unwrap().clone();
 
-    Ok(link_store_populated)
+    Ok(UploadBatch {
+        urls: link_store_populated,
+        errors,
+    })
 }
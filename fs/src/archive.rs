@@ -1,15 +1,45 @@
 use std::io::prelude::*;
-use std::io::{Seek, Write};
+use std::io::{self, Seek, Write};
 use std::iter::Iterator;
 use zip::result::ZipError;
-use zip::write::FileOptions;
+use zip::write::{FileOptions, SimpleFileOptions};
+use zip::AesMode;
 
-use mtzip::ZipArchive;
-use std::fs::File;
-use std::path::{Path, PathBuf};
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use flate2::{write::GzEncoder, Compression};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::Path;
+use tokio::task::spawn_blocking;
 use walkdir::{DirEntry, WalkDir};
 
-use crate::FileManager;
+use crate::{
+    interface::{ArchiveFormat, ArchiveOptions, DownloadError, DuplicatePolicy, MetadataMode},
+    manifest::{
+        get_duplicates_manifest, get_manifest, manifest_to_csv, manifest_to_json, FileMetadata,
+        ManifestEntry, MANIFEST_CSV_NAME, MANIFEST_JSON_NAME, METADATA_JSONL_NAME,
+        METADATA_SIDECAR_EXT,
+    },
+    FileManager,
+};
+
+pub static ERRORS_JSON_NAME: &str = "errors.json";
+pub static DELETIONS_JSON_NAME: &str = "deletions.json";
+pub static DUPLICATES_TXT_NAME: &str = "duplicates.txt";
+// Room kept for the headers and the manifest line of every entry when splitting into parts
+static ARCHIVE_ENTRY_OVERHEAD: u64 = 1024;
+
+// Listings written next to the files, only into the first part when split
+#[derive(Default)]
+pub struct ArchiveReports {
+    pub errors: Vec<DownloadError>,
+    // Files of the earlier manifest which are gone, only set for incremental downloads
+    pub deletions: Option<Vec<ManifestEntry>>,
+    // Earlier manifest entries of files left out because they did not change
+    // Keeping them in the manifest lets every incremental archive be the base of the next one
+    pub unchanged: Vec<ManifestEntry>,
+}
 
 // REFERENCE -> https://github.com/zip-rs/zip/blob/master/examples/write_dir.rs
 
@@ -30,7 +60,7 @@ where
     T: Write + Seek,
 {
     let mut zip = zip::ZipWriter::new(writer);
-    let options = FileOptions::default()
+    let options = SimpleFileOptions::default()
         .compression_method(method)
         .compression_level(Some(9))
         .unix_permissions(0o755);
@@ -81,16 +111,681 @@ fn doit(
     Ok(())
 }
 
-pub async fn archive_v2(files: Vec<FileManager>) {
-    let zipper = ZipArchive::default();
+// Content which does not get any smaller when compressed again
+static PRECOMPRESSED_MIME_TYPES: [&str; 20] = [
+    "application/pdf",
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/zstd",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/x-7z-compressed",
+    "application/vnd.rar",
+    "application/x-rar-compressed",
+    "application/epub+zip",
+    "image/jpeg",
+    "image/png",
+    "image/gif",
+    "image/webp",
+    "image/heic",
+    "image/avif",
+    "audio/mpeg",
+    "audio/aac",
+    "audio/ogg",
+];
+static PRECOMPRESSED_MIME_PREFIXES: [&str; 4] = [
+    "video/",
+    // Office files are zip containers
+    "application/vnd.openxmlformats-officedocument.",
+    "application/vnd.oasis.opendocument.",
+    "application/x-vnd.oasis.opendocument.",
+];
+
+pub fn is_precompressed(mime_type: &str) -> bool {
+    PRECOMPRESSED_MIME_TYPES.contains(&mime_type)
+        || PRECOMPRESSED_MIME_PREFIXES
+            .iter()
+            .any(|prefix| mime_type.starts_with(prefix))
+}
+
+enum EntrySource {
+    Path(String),
+    Data(Vec<u8>),
+}
+
+struct ArchiveEntry {
+    name: String,
+    source: EntrySource,
+    // Stored as is instead of being compressed again
+    is_precompressed: bool,
+    // Time the file was last modified on Drive, the time of writing otherwise
+    modified_time: Option<DateTime<Utc>>,
+}
+
+impl ArchiveEntry {
+    fn from_data(name: &str, data: Vec<u8>) -> Self {
+        Self {
+            name: name.to_string(),
+            source: EntrySource::Data(data),
+            is_precompressed: false,
+            modified_time: None,
+        }
+    }
+
+    fn from_file(file: &FileManager) -> Self {
+        Self {
+            name: file.get_relative_path(),
+            source: EntrySource::Path(file.get_optimal_target_path()),
+            is_precompressed: is_precompressed(file.mime_type.as_str()),
+            modified_time: file.file.modified_time,
+        }
+    }
+
+    // Zip timestamps cannot go before 1980, such entries get the time of writing
+    fn get_zip_modified_time(&self) -> Option<zip::DateTime> {
+        let modified_time = self.modified_time?;
+        zip::DateTime::from_date_and_time(
+            modified_time.year().try_into().ok()?,
+            modified_time.month() as u8,
+            modified_time.day() as u8,
+            modified_time.hour() as u8,
+            modified_time.minute() as u8,
+            modified_time.second() as u8,
+        )
+        .ok()
+    }
+
+    fn get_size(&self) -> io::Result<u64> {
+        match &self.source {
+            EntrySource::Path(path) => Ok(fs::metadata(path)?.len()),
+            EntrySource::Data(data) => Ok(data.len() as u64),
+        }
+    }
+}
+
+// Entries of 4 GB and more need Zip64 sizes, which have to be asked for before the entry is written
+fn get_zip_file_options<'a>(
+    entry: &ArchiveEntry,
+    size: u64,
+    options: &'a ArchiveOptions,
+) -> FileOptions<'a, ()> {
+    let file_options = if entry.is_precompressed || options.store_only {
+        SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored)
+    } else {
+        SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .compression_level(Some(options.get_compression_level() as i64))
+    };
+    let mut file_options = file_options.large_file(size >= u32::MAX as u64);
+    if let Some(password) = options.password.as_ref() {
+        file_options = file_options.with_aes_encryption(AesMode::Aes256, password.expose());
+    }
+    if let Some(modified_time) = entry.get_zip_modified_time() {
+        file_options = file_options.last_modified_time(modified_time);
+    }
+    file_options
+}
+
+fn write_zip(entries: Vec<ArchiveEntry>, options: &ArchiveOptions, file: File) -> Result<()> {
+    let mut zip = zip::ZipWriter::new(file);
+
+    for entry in entries {
+        let file_options = get_zip_file_options(&entry, entry.get_size()?, options);
+        zip.start_file(entry.name, file_options)?;
+        match entry.source {
+            EntrySource::Path(path) => {
+                io::copy(&mut File::open(path)?, &mut zip)?;
+            }
+            EntrySource::Data(data) => zip.write_all(&data)?,
+        }
+    }
+
+    zip.finish()?;
+    Ok(())
+}
+
+enum TarEncoder {
+    Plain(File),
+    Gzip(GzEncoder<File>),
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+// Compressed tars are written as a sequence of gzip members or zstd frames
+// Starting a new one whenever the level changes lets every entry get its own level
+struct TarWriter {
+    format: ArchiveFormat,
+    compression_level: i32,
+    encoder: Option<TarEncoder>,
+}
+
+impl TarWriter {
+    fn new(format: ArchiveFormat, compression_level: i32, file: File) -> io::Result<Self> {
+        Ok(Self {
+            format,
+            compression_level,
+            encoder: Some(Self::create_encoder(format, compression_level, file)?),
+        })
+    }
+
+    fn create_encoder(
+        format: ArchiveFormat,
+        compression_level: i32,
+        file: File,
+    ) -> io::Result<TarEncoder> {
+        Ok(match format {
+            ArchiveFormat::TarGz => TarEncoder::Gzip(GzEncoder::new(
+                file,
+                Compression::new(compression_level as u32),
+            )),
+            ArchiveFormat::TarZst => TarEncoder::Zstd(zstd::Encoder::new(file, compression_level)?),
+            _ => TarEncoder::Plain(file),
+        })
+    }
+
+    // Level closest to storing the content as is
+    fn get_store_level(&self) -> i32 {
+        match self.format {
+            // zstd falls back to raw blocks on its own for incompressible content
+            ArchiveFormat::TarZst => 1,
+            _ => 0,
+        }
+    }
+
+    fn set_compression_level(&mut self, compression_level: i32) -> io::Result<()> {
+        if self.compression_level == compression_level {
+            return Ok(());
+        }
+        let file = self.finish()?;
+        self.encoder = Some(Self::create_encoder(self.format, compression_level, file)?);
+        self.compression_level = compression_level;
+        Ok(())
+    }
+
+    fn get_encoder(&mut self) -> &mut dyn Write {
+        match self.encoder.as_mut().unwrap() {
+            TarEncoder::Plain(file) => file,
+            TarEncoder::Gzip(encoder) => encoder,
+            TarEncoder::Zstd(encoder) => encoder,
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<File> {
+        match self.encoder.take().unwrap() {
+            TarEncoder::Plain(file) => Ok(file),
+            TarEncoder::Gzip(encoder) => encoder.finish(),
+            TarEncoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl Write for TarWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.get_encoder().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_encoder().flush()
+    }
+}
+
+fn write_tar(entries: Vec<ArchiveEntry>, options: &ArchiveOptions, file: File) -> Result<()> {
+    let compression_level = options.get_compression_level();
+    let mut tar = tar::Builder::new(TarWriter::new(options.format, compression_level, file)?);
+
+    for entry in entries {
+        let writer = tar.get_mut();
+        let entry_level = if entry.is_precompressed {
+            writer.get_store_level()
+        } else {
+            compression_level
+        };
+        writer.set_compression_level(entry_level)?;
+
+        let modified_time = entry
+            .modified_time
+            .unwrap_or_else(Utc::now)
+            .timestamp()
+            .max(0) as u64;
+        match entry.source {
+            EntrySource::Path(path) => {
+                let file = File::open(path)?;
+                let mut header = tar::Header::new_gnu();
+                header.set_metadata(&file.metadata()?);
+                header.set_mtime(modified_time);
+                tar.append_data(&mut header, entry.name, file)?;
+            }
+            EntrySource::Data(data) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(modified_time);
+                tar.append_data(&mut header, entry.name, data.as_slice())?;
+            }
+        }
+    }
+
+    tar.into_inner()?.finish()?;
+    Ok(())
+}
+
+// Files are stored as is, duplicates are only listed along with the path of the stored file
+fn get_entries(
+    files: &[FileManager],
+    duplicates: &[(FileManager, String)],
+    reports: &ArchiveReports,
+    options: &ArchiveOptions,
+) -> Result<Vec<ArchiveEntry>> {
+    let mut entries = Vec::new();
+
+    // Every archive describes its own contents
+    let mut manifest = get_manifest(files);
+    manifest.extend(get_duplicates_manifest(duplicates));
+    manifest.extend(reports.unchanged.iter().cloned());
+    entries.push(ArchiveEntry::from_data(
+        MANIFEST_JSON_NAME,
+        manifest_to_json(&manifest)?,
+    ));
+    if options.manifest_csv {
+        entries.push(ArchiveEntry::from_data(
+            MANIFEST_CSV_NAME,
+            manifest_to_csv(&manifest)?,
+        ));
+    }
+
+    // Files which could not be downloaded are listed instead of silently missing
+    if !reports.errors.is_empty() {
+        entries.push(ArchiveEntry::from_data(
+            ERRORS_JSON_NAME,
+            serde_json::to_vec_pretty(&reports.errors)?,
+        ));
+    }
+    // Incremental archives always list deletions so that restoring them is unambiguous
+    if let Some(deletions) = reports.deletions.as_ref() {
+        entries.push(ArchiveEntry::from_data(
+            DELETIONS_JSON_NAME,
+            serde_json::to_vec_pretty(deletions)?,
+        ));
+    }
+
+    // Every line of the mapping reads `duplicate path -> stored path`
+    if !duplicates.is_empty() {
+        let duplicates_txt = duplicates
+            .iter()
+            .map(|(file, stored_path)| format!("{} -> {}\n", file.get_relative_path(), stored_path))
+            .collect::<String>();
+        entries.push(ArchiveEntry::from_data(
+            DUPLICATES_TXT_NAME,
+            duplicates_txt.into_bytes(),
+        ));
+    }
+
+    match options.metadata {
+        MetadataMode::Omit => {}
+        MetadataMode::Sidecar => {
+            for file in files.iter() {
+                let file_metadata = FileMetadata::new(file);
+                entries.push(ArchiveEntry::from_data(
+                    format!("{}.{}", file_metadata.archive_path, METADATA_SIDECAR_EXT).as_str(),
+                    serde_json::to_vec_pretty(&file_metadata)?,
+                ));
+            }
+        }
+        MetadataMode::Jsonl => {
+            let mut metadata_lines = Vec::new();
+            for file in files.iter() {
+                serde_json::to_writer(&mut metadata_lines, &FileMetadata::new(file))?;
+                metadata_lines.push(b'\n');
+            }
+            entries.push(ArchiveEntry::from_data(METADATA_JSONL_NAME, metadata_lines));
+        }
+    }
+
+    entries.extend(files.iter().map(ArchiveEntry::from_file));
+    Ok(entries)
+}
+
+// Keeps the first file by path of every content and pairs the others with the path of that file
+fn get_duplicates(files: Vec<FileManager>) -> (Vec<FileManager>, Vec<(FileManager, String)>) {
+    let mut sorted_files = files;
+    sorted_files.sort_by_key(|file| file.get_relative_path());
+
+    let mut stored_paths = HashMap::<String, String>::new();
+    let mut stored_files = Vec::new();
+    let mut duplicates = Vec::new();
+    for file in sorted_files {
+        let content_key = match file.get_content_key() {
+            Some(content_key) => content_key,
+            None => {
+                stored_files.push(file);
+                continue;
+            }
+        };
+        match stored_paths.get(&content_key) {
+            Some(stored_path) => duplicates.push((file, stored_path.clone())),
+            None => {
+                stored_paths.insert(content_key, file.get_relative_path());
+                stored_files.push(file);
+            }
+        }
+    }
+    (stored_files, duplicates)
+}
+
+fn write_archive(
+    files: &[FileManager],
+    duplicates: &[(FileManager, String)],
+    reports: &ArchiveReports,
+    options: &ArchiveOptions,
+    output_path: &str,
+) -> Result<()> {
+    let entries = get_entries(files, duplicates, reports, options)?;
+    let file = File::create(output_path)?;
+    match options.format {
+        ArchiveFormat::Zip => write_zip(entries, options, file),
+        _ => write_tar(entries, options, file),
+    }
+}
+
+// Size of the listings only the first part carries
+fn get_reports_size(
+    duplicates: &[(FileManager, String)],
+    reports: &ArchiveReports,
+    options: &ArchiveOptions,
+) -> Result<u64> {
+    Ok(get_entries(&[], duplicates, reports, options)?
+        .iter()
+        .map(|entry| Ok(entry.get_size()? + ARCHIVE_ENTRY_OVERHEAD))
+        .sum::<io::Result<u64>>()?)
+}
+
+// Packs the files greedily into parts without ever splitting a single file
+// A file larger than the limit ends up alone in a part of its own
+// The first part starts out with the size of the listings it carries
+fn split_into_parts(
+    files: Vec<FileManager>,
+    max_part_size: u64,
+    reports_size: u64,
+) -> Vec<Vec<FileManager>> {
+    let mut parts: Vec<Vec<FileManager>> = vec![Vec::new()];
+    let mut part_size = reports_size;
 
     for file in files {
-        zipper.add_file(
-            PathBuf::from(file.get_optimal_target_path()),
-            file.get_relative_path(),
-        );
+        let file_size = fs::metadata(file.get_optimal_target_path())
+            .map(|metadata| metadata.len())
+            .unwrap_or_default()
+            + ARCHIVE_ENTRY_OVERHEAD;
+
+        if part_size > 0 && part_size + file_size > max_part_size {
+            parts.push(Vec::new());
+            part_size = 0;
+        }
+        parts.last_mut().unwrap().push(file);
+        part_size += file_size;
+    }
+
+    parts
+}
+
+// Writes every part as a standalone archive and stores them all inside a single zip
+fn write_parts(
+    files: Vec<FileManager>,
+    duplicates: &[(FileManager, String)],
+    reports: &ArchiveReports,
+    options: &ArchiveOptions,
+    max_part_size: u64,
+    output_path: &str,
+) -> Result<()> {
+    let mut zip = zip::ZipWriter::new(File::create(output_path)?);
+
+    let reports_size = get_reports_size(duplicates, reports, options)?;
+    let parts = split_into_parts(files, max_part_size, reports_size);
+    for (part_idx, part) in parts.iter().enumerate() {
+        let part_path = format!("{}.part{}", output_path, part_idx + 1);
+        // Errors, deletions and duplicates are only listed once, inside the first part
+        let no_reports = ArchiveReports::default();
+        let (part_duplicates, part_reports) = match part_idx {
+            0 => (duplicates, reports),
+            _ => (&[][..], &no_reports),
+        };
+        write_archive(
+            part,
+            part_duplicates,
+            part_reports,
+            options,
+            part_path.as_str(),
+        )?;
+
+        // A single file bigger than the limit still gets its own part, so the
+        // actual size decides whether the part needs Zip64
+        let part_size = fs::metadata(part_path.as_str())?.len();
+        let part_options = SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored)
+            .large_file(part_size >= u32::MAX as u64);
+        zip.start_file(
+            format!("part{}.{}", part_idx + 1, options.format.get_ext()),
+            part_options,
+        )?;
+        io::copy(&mut File::open(part_path.as_str())?, &mut zip)?;
+        fs::remove_file(part_path)?;
+    }
+
+    zip.finish()?;
+    Ok(())
+}
+
+// Writing and compressing the archive is blocking work so it stays off the runtime
+pub async fn archive_v2(
+    files: Vec<FileManager>,
+    reports: ArchiveReports,
+    options: ArchiveOptions,
+    output_path: &str,
+) -> Result<()> {
+    let output_path = output_path.to_string();
+    spawn_blocking(move || write_output(files, reports, options, output_path.as_str())).await?
+}
+
+fn write_output(
+    files: Vec<FileManager>,
+    reports: ArchiveReports,
+    options: ArchiveOptions,
+    output_path: &str,
+) -> Result<()> {
+    // Duplicates are found over every file so that a content is stored once across all parts
+    let (files, duplicates) = match options.duplicates {
+        DuplicatePolicy::Keep => (files, Vec::new()),
+        DuplicatePolicy::StoreOnce => get_duplicates(files),
+    };
+
+    match options.max_part_size {
+        Some(max_part_size) => write_parts(
+            files,
+            &duplicates,
+            &reports,
+            &options,
+            max_part_size,
+            output_path,
+        ),
+        None => write_archive(&files, &duplicates, &reports, &options, output_path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    // Just past the 4 GB limit of plain zip sizes and offsets
+    const LARGE_FILE_SIZE: u64 = u32::MAX as u64 + 1024 * 1024;
+
+    // Sparse files take no disk space, so archives above 4 GB stay cheap to build
+    fn create_sparse_file(name: &str, size: u64) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        File::create(&path).unwrap().set_len(size).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn get_temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        path.to_string_lossy().to_string()
     }
 
-    let mut file = File::create("tmp/output.zip").unwrap();
-    zipper.write(&mut file);
+    fn path_entry(name: &str, path: &str, is_precompressed: bool) -> ArchiveEntry {
+        ArchiveEntry {
+            name: name.to_string(),
+            source: EntrySource::Path(path.to_string()),
+            is_precompressed,
+            modified_time: None,
+        }
+    }
+
+    // The output is written out in full, run with `cargo test -p fs -- --ignored`
+    #[test]
+    #[ignore = "writes more than 4 GB to the temp dir"]
+    fn zip_stores_file_larger_than_4gb() {
+        let source_path = create_sparse_file("stored.bin", LARGE_FILE_SIZE);
+        let output_path = get_temp_path("stored.zip");
+        let entries = vec![
+            path_entry("lecture.mp4", source_path.as_str(), true),
+            ArchiveEntry::from_data("after.txt", b"after".to_vec()),
+        ];
+        write_zip(
+            entries,
+            &ArchiveOptions::default(),
+            File::create(output_path.as_str()).unwrap(),
+        )
+        .unwrap();
+        assert!(fs::metadata(output_path.as_str()).unwrap().len() > LARGE_FILE_SIZE);
+
+        let mut zip = zip::ZipArchive::new(File::open(output_path.as_str()).unwrap()).unwrap();
+        assert_eq!(zip.by_name("lecture.mp4").unwrap().size(), LARGE_FILE_SIZE);
+        // The entry after the large file starts beyond 4 GB and needs a Zip64 offset
+        let mut after = zip.by_name("after.txt").unwrap();
+        assert!(after.header_start() > u32::MAX as u64);
+        let mut content = String::new();
+        after.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "after");
+
+        fs::remove_file(source_path).unwrap();
+        fs::remove_file(output_path).unwrap();
+    }
+
+    // Only the size the Zip64 decision is based on is mocked, the entry itself stays tiny
+    #[test]
+    fn zip_writes_zip64_extra_field_for_large_entries() {
+        let entry = ArchiveEntry::from_data("lecture.mp4", b"lecture".to_vec());
+        let options = ArchiveOptions::default();
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        zip.start_file(
+            entry.name.as_str(),
+            get_zip_file_options(&entry, LARGE_FILE_SIZE, &options),
+        )
+        .unwrap();
+        zip.write_all(b"lecture").unwrap();
+        let output = zip.finish().unwrap().into_inner();
+
+        // The local header is followed by the name and the extra fields
+        assert_eq!(&output[..4], b"PK\x03\x04");
+        let name_length = u16::from_le_bytes([output[26], output[27]]) as usize;
+        let extra_length = u16::from_le_bytes([output[28], output[29]]) as usize;
+        let extra = &output[30 + name_length..30 + name_length + extra_length];
+        // 0x0001 is the Zip64 extended information field
+        assert!(extra.len() >= 4);
+        assert_eq!(u16::from_le_bytes([extra[0], extra[1]]), 0x0001);
+        assert!(u16::from_le_bytes([extra[2], extra[3]]) >= 16);
+
+        let mut zip = zip::ZipArchive::new(io::Cursor::new(output)).unwrap();
+        let mut content = String::new();
+        zip.by_name("lecture.mp4")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "lecture");
+    }
+
+    #[test]
+    fn zip_stays_plain_for_small_entries() {
+        let entry = ArchiveEntry::from_data("notes.txt", b"notes".to_vec());
+        let options = ArchiveOptions::default();
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        zip.start_file(
+            entry.name.as_str(),
+            get_zip_file_options(&entry, entry.get_size().unwrap(), &options),
+        )
+        .unwrap();
+        zip.write_all(b"notes").unwrap();
+        let output = zip.finish().unwrap().into_inner();
+
+        let extra_length = u16::from_le_bytes([output[28], output[29]]);
+        assert_eq!(extra_length, 0);
+    }
+
+    #[test]
+    fn zip_compresses_file_larger_than_4gb() {
+        let source_path = create_sparse_file("deflated.bin", LARGE_FILE_SIZE);
+        let output_path = get_temp_path("deflated.zip");
+        let options = ArchiveOptions {
+            compression_level: Some(1),
+            ..Default::default()
+        };
+        write_zip(
+            vec![path_entry("recording.raw", source_path.as_str(), false)],
+            &options,
+            File::create(output_path.as_str()).unwrap(),
+        )
+        .unwrap();
+
+        let mut zip = zip::ZipArchive::new(File::open(output_path.as_str()).unwrap()).unwrap();
+        let entry = zip.by_name("recording.raw").unwrap();
+        assert_eq!(entry.size(), LARGE_FILE_SIZE);
+        assert!(entry.compressed_size() < u32::MAX as u64);
+
+        fs::remove_file(source_path).unwrap();
+        fs::remove_file(output_path).unwrap();
+    }
+
+    #[test]
+    fn zip_holds_more_than_65535_entries() {
+        let output_path = get_temp_path("entries.zip");
+        let entry_count = u16::MAX as usize + 1000;
+        let entries = (0..entry_count)
+            .map(|idx| ArchiveEntry::from_data(format!("{idx}.txt").as_str(), Vec::new()))
+            .collect();
+        write_zip(
+            entries,
+            &ArchiveOptions::default(),
+            File::create(output_path.as_str()).unwrap(),
+        )
+        .unwrap();
+
+        let zip = zip::ZipArchive::new(File::open(output_path.as_str()).unwrap()).unwrap();
+        assert_eq!(zip.len(), entry_count);
+
+        fs::remove_file(output_path).unwrap();
+    }
+
+    // The output is written out in full, run with `cargo test -p fs -- --ignored`
+    #[test]
+    #[ignore = "writes more than 4 GB to the temp dir"]
+    fn tar_stores_file_larger_than_4gb() {
+        let source_path = create_sparse_file("tar.bin", LARGE_FILE_SIZE);
+        let output_path = get_temp_path("large.tar");
+        let options = ArchiveOptions {
+            format: ArchiveFormat::Tar,
+            ..Default::default()
+        };
+        write_tar(
+            vec![path_entry("lecture.mp4", source_path.as_str(), true)],
+            &options,
+            File::create(output_path.as_str()).unwrap(),
+        )
+        .unwrap();
+
+        let mut tar = tar::Archive::new(File::open(output_path.as_str()).unwrap());
+        let entry = tar.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.header().size().unwrap(), LARGE_FILE_SIZE);
+
+        fs::remove_file(source_path).unwrap();
+        fs::remove_file(output_path).unwrap();
+    }
 }
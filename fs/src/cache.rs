@@ -1,5 +1,6 @@
 use csv::{ReaderBuilder, StringRecord, Writer};
 use dashmap::DashMap;
+use md5::{Digest, Md5};
 use redis::{Commands, Connection, FromRedisValue, RedisError, ToRedisArgs};
 use redis_macros::{FromRedisValue, ToRedisArgs};
 use serde::{Deserialize, Serialize};
@@ -7,11 +8,13 @@ use std::{
     collections::HashMap,
     env,
     fs::{self, File, OpenOptions},
-    io::Write,
+    io::{self, Write},
     path::Path,
     sync::{Arc, Mutex},
 };
 
+use tokio::task::spawn_blocking;
+
 use crate::{
     FileManager, CACHE_FILES_PATH, CACHE_KEY_STORE_PATH, TMP_BASE_PATH, TMP_CACHE_PATH,
     TMP_FILES_COMPRESSED_BASE_PATH, TMP_FILES_OUTPUT_BASE_PATH, TMP_FILES_UNCOMPRESSED_BASE_PATH,
@@ -24,13 +27,54 @@ pub struct RedisRequest<T> {
     pub data: T,
 }
 
+#[derive(Clone, Debug)]
+pub struct CacheEntry {
+    pub path: String,
+    // Drive md5Checksum of the content the entry was created from
+    pub md5_checksum: Option<String>,
+    // Size and md5 of the cached file itself, which differ from Drive's once compressed
+    pub size: Option<u64>,
+    pub content_md5: Option<String>,
+}
+
+impl CacheEntry {
+    // Whether the file on disk still has the size it was cached with, cheap enough for every lookup
+    // Entries written before the file itself was tracked cannot be trusted
+    pub fn is_intact(&self) -> bool {
+        let (Some(size), Some(_)) = (self.size, self.content_md5.as_ref()) else {
+            return false;
+        };
+        matches!(fs::metadata(self.path.as_str()), Ok(metadata) if metadata.len() == size)
+    }
+
+    // Whether the content of the file on disk is still exactly what was cached
+    fn has_cached_content(&self) -> bool {
+        match (self.content_md5.as_ref(), get_file_md5(self.path.as_str())) {
+            (Some(content_md5), Ok(file_md5)) => file_md5 == *content_md5,
+            _ => false,
+        }
+    }
+}
+
+fn get_file_md5(path: &str) -> io::Result<String> {
+    let mut hasher = Md5::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
 #[derive()]
 pub struct CacheManager {
-    // HashMap<FileID -> RevisionID -> Path>
-    pub store: DashMap<String, HashMap<String, String>>,
+    // HashMap<FileID -> CacheKey (RevisionID + variant) -> CacheEntry>
+    pub store: DashMap<String, HashMap<String, CacheEntry>>,
     pub redis: Connection,
 }
 
+impl Default for CacheManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CacheManager {
     pub fn new() -> Self {
         Self::run_fs_checks();
@@ -53,12 +97,22 @@ impl CacheManager {
         self.redis.set::<String, T, String>(key, value).unwrap();
     }
 
+    pub fn set_to_redis_with_ttl<T: ToRedisArgs>(&mut self, key: String, value: T, seconds: u64) {
+        self.redis
+            .set_ex::<String, T, String>(key, value, seconds)
+            .unwrap();
+    }
+
+    pub fn delete_from_redis(&mut self, key: String) {
+        self.redis.del::<String, ()>(key).unwrap();
+    }
+
     pub fn get_from_redis<T: FromRedisValue + Default>(
         &mut self,
         key: String,
     ) -> Result<T, RedisError> {
         let redis_response: Result<T, RedisError> = self.redis.get(key);
-        return redis_response;
+        redis_response
     }
 
     pub fn run_fs_checks() {
@@ -77,28 +131,46 @@ impl CacheManager {
                 .open(CACHE_KEY_STORE_PATH)
                 .unwrap();
 
-            file.write("file_id,revision_id,path,file_name,timestamp\n".as_bytes())
-                .unwrap();
+            file.write_all(
+                "file_id,revision_id,path,file_name,timestamp,md5_checksum,size,content_md5\n"
+                    .as_bytes(),
+            )
+            .unwrap();
         }
     }
 
     fn parse_row_in_memory(&mut self, record: StringRecord) {
-        let file_id = record.get(0).clone().unwrap().to_string();
-        let revision_id = record.get(1).clone().unwrap().to_string();
-        let target_path = record.get(2).clone().unwrap().to_string();
+        let file_id = record.get(0).unwrap().to_string();
+        let revision_id = record.get(1).unwrap().to_string();
+        let cache_entry = CacheEntry {
+            path: record.get(2).unwrap().to_string(),
+            // Rows written before checksums were tracked do not have the column
+            md5_checksum: record
+                .get(5)
+                .filter(|md5_checksum| !md5_checksum.is_empty())
+                .map(|md5_checksum| md5_checksum.to_string()),
+            size: record.get(6).and_then(|size| size.parse().ok()),
+            content_md5: record
+                .get(7)
+                .filter(|content_md5| !content_md5.is_empty())
+                .map(|content_md5| content_md5.to_string()),
+        };
 
         self.store
             .entry(file_id)
             .and_modify(|revision_map| {
-                revision_map.insert(revision_id.clone(), target_path.clone());
+                revision_map.insert(revision_id.clone(), cache_entry.clone());
             })
-            .or_insert(HashMap::from([(revision_id, target_path)]));
+            .or_insert(HashMap::from([(revision_id, cache_entry)]));
     }
 
     pub fn initialize(&mut self) {
         let file = File::open(CACHE_KEY_STORE_PATH).expect("Cant open file");
 
-        let mut rdr = ReaderBuilder::new().delimiter(b',').from_reader(file);
+        let mut rdr = ReaderBuilder::new()
+            .delimiter(b',')
+            .flexible(true)
+            .from_reader(file);
 
         for result in rdr.records() {
             let record = result.unwrap();
@@ -106,12 +178,62 @@ impl CacheManager {
         }
     }
 
+    // Hashing the cached files is too slow for every lookup, so their content is checked once at startup
+    // Entries whose file changed are dropped so that the file gets downloaded again
+    pub async fn verify_entries(cache_manager: Arc<Mutex<CacheManager>>) {
+        let cache_entries = cache_manager
+            .lock()
+            .unwrap()
+            .store
+            .iter()
+            .flat_map(|revision_map| {
+                revision_map
+                    .value()
+                    .iter()
+                    .map(|(cache_key, cache_entry)| {
+                        (
+                            revision_map.key().clone(),
+                            cache_key.clone(),
+                            cache_entry.clone(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let corrupted_entries = spawn_blocking(move || {
+            cache_entries
+                .into_iter()
+                .filter(|(_, _, cache_entry)| !cache_entry.has_cached_content())
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap();
+
+        let cache_manager = cache_manager.lock().unwrap();
+        for (file_id, cache_key, cache_entry) in corrupted_entries {
+            println!(
+                "{} | Cached file does not match its entry",
+                cache_entry.path
+            );
+            if let Some(mut revision_map) = cache_manager.store.get_mut(&file_id) {
+                // Entries stored again while verifying are left alone
+                if revision_map
+                    .get(&cache_key)
+                    .is_some_and(|stored_entry| stored_entry.content_md5 == cache_entry.content_md5)
+                {
+                    revision_map.remove(&cache_key);
+                }
+            }
+        }
+    }
+
     pub fn get_cache_file_path(fm: FileManager) -> String {
         format!(
             "{}/{}_{}.{}",
             CACHE_FILES_PATH,
             fm.file.id.clone().unwrap(),
-            fm.file.head_revision_id.clone().unwrap_or("".to_string()),
+            fm.get_cache_key(),
             fm.ext.clone()
         )
     }
@@ -121,7 +243,6 @@ impl CacheManager {
         cache_manager: Arc<Mutex<CacheManager>>,
     ) {
         let file = OpenOptions::new()
-            .write(true)
             .append(true)
             .open(CACHE_KEY_STORE_PATH)
             .unwrap();
@@ -131,31 +252,39 @@ impl CacheManager {
             let cache_file_path = Self::get_cache_file_path(fm.clone());
 
             // Update only if not already cached
-            if !fm.is_cached {
+            if !fm.is_cached && !fm.is_placeholder {
                 fs::copy(fm.get_optimal_target_path(), cache_file_path.clone()).unwrap();
+                let size = fs::metadata(cache_file_path.as_str()).unwrap().len();
+                let content_md5 = get_file_md5(cache_file_path.as_str()).unwrap();
 
                 wtr.write_record(&[
                     fm.file.id.clone().unwrap(),
-                    fm.get_file_revision_id().clone(),
+                    fm.get_cache_key(),
                     cache_file_path.clone(),
                     fm.file_name.clone().replace(",", ""),
                     chrono::offset::Local::now().to_string(),
+                    fm.file.md5_checksum.clone().unwrap_or_default(),
+                    size.to_string(),
+                    content_md5.clone(),
                 ])
                 .unwrap();
 
                 // Update in-memory
+                let cache_entry = CacheEntry {
+                    path: cache_file_path.clone(),
+                    md5_checksum: fm.file.md5_checksum.clone(),
+                    size: Some(size),
+                    content_md5: Some(content_md5),
+                };
                 cache_manager
                     .lock()
                     .unwrap()
                     .store
                     .entry(fm.file.id.clone().unwrap())
                     .and_modify(|revision_map| {
-                        revision_map.insert(fm.get_file_revision_id(), cache_file_path.clone());
+                        revision_map.insert(fm.get_cache_key(), cache_entry.clone());
                     })
-                    .or_insert(HashMap::from([(
-                        fm.get_file_revision_id(),
-                        cache_file_path,
-                    )]));
+                    .or_insert(HashMap::from([(fm.get_cache_key(), cache_entry)]));
             }
 
             // Cleanup
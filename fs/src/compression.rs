@@ -1,11 +1,28 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    fs::File,
+    io::{Read, Write},
+    sync::{Arc, Mutex},
+};
 
 use futures::future::join_all;
+use image::{
+    codecs::{
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType, PngEncoder},
+    },
+    imageops, ImageFormat,
+};
 use pdfshrink::gs_command;
-use tokio::spawn;
+use tokio::{spawn, task::spawn_blocking};
+use zip::{result::ZipResult, write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
 
 use crate::FileManager;
 
+pub static OFFICE_EXTENSIONS: [&str; 3] = ["pptx", "docx", "xlsx"];
+static OFFICE_MEDIA_DIRS: [&str; 3] = ["ppt/media/", "word/media/", "xl/media/"];
+static OFFICE_MEDIA_JPEG_QUALITY: u8 = 70;
+static OFFICE_MEDIA_MAX_DIMENSION: u32 = 2048;
+
 async fn compress_pdf(
     file: Arc<FileManager>,
     file_idx: usize,
@@ -19,9 +36,14 @@ async fn compress_pdf(
     }
 
     // We call the get_optimal_target_path() which should return the cached path if present
-    let error = gs_command(file.get_optimal_target_path(), output_path.clone()).spawn();
-    if error.is_ok() {
-        error.unwrap().wait().unwrap();
+    let mut command = gs_command(file.get_optimal_target_path(), output_path.clone());
+    let succeeded = spawn_blocking(move || match command.spawn() {
+        Ok(mut child) => child.wait().map(|status| status.success()).unwrap_or(false),
+        Err(_) => false,
+    })
+    .await
+    .unwrap_or(false);
+    if succeeded {
         fm_list
             .lock()
             .unwrap()
@@ -31,19 +53,131 @@ async fn compress_pdf(
     }
 }
 
+// Re-encodes a single embedded image and returns it only if it ends up smaller
+fn recompress_image(name: &str, content: &[u8]) -> Option<Vec<u8>> {
+    let format = ImageFormat::from_path(name).ok()?;
+    let mut image = image::load_from_memory_with_format(content, format).ok()?;
+
+    // Slides only reference the image by its display size so downscaling keeps the layout intact
+    if image.width() > OFFICE_MEDIA_MAX_DIMENSION || image.height() > OFFICE_MEDIA_MAX_DIMENSION {
+        image = image.resize(
+            OFFICE_MEDIA_MAX_DIMENSION,
+            OFFICE_MEDIA_MAX_DIMENSION,
+            imageops::FilterType::Lanczos3,
+        );
+    }
+
+    let mut output = Vec::new();
+    match format {
+        ImageFormat::Jpeg => image
+            .write_with_encoder(JpegEncoder::new_with_quality(
+                &mut output,
+                OFFICE_MEDIA_JPEG_QUALITY,
+            ))
+            .ok()?,
+        ImageFormat::Png => image
+            .write_with_encoder(PngEncoder::new_with_quality(
+                &mut output,
+                CompressionType::Best,
+                FilterType::Adaptive,
+            ))
+            .ok()?,
+        _ => return None,
+    }
+
+    if output.len() < content.len() {
+        Some(output)
+    } else {
+        None
+    }
+}
+
+// Copies the office zip container entry by entry, recompressing everything inside the media folders
+fn repack_office_file(input_path: &str, output_path: &str) -> ZipResult<()> {
+    let mut source = ZipArchive::new(File::open(input_path)?)?;
+    let mut target = ZipWriter::new(File::create(output_path)?);
+
+    for entry_idx in 0..source.len() {
+        let mut entry = source.by_index(entry_idx)?;
+        let name = entry.name().to_string();
+
+        if !OFFICE_MEDIA_DIRS.iter().any(|dir| name.starts_with(dir)) {
+            target.raw_copy_file(entry)?;
+            continue;
+        }
+
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        let content = recompress_image(name.as_str(), &content).unwrap_or(content);
+
+        // Images are already compressed so deflating them again only wastes CPU
+        target.start_file(
+            name,
+            SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+        )?;
+        target.write_all(&content)?;
+    }
+
+    target.finish()?;
+    Ok(())
+}
+
+async fn compress_office(
+    file: Arc<FileManager>,
+    file_idx: usize,
+    fm_list: Arc<Mutex<Vec<FileManager>>>,
+) {
+    let output_path = file.get_compressed_target_path();
+
+    // Dont compress if already cached -> compressed
+    if file.is_cached {
+        return;
+    }
+
+    // Decoding and encoding the images is CPU bound so it stays off the runtime
+    let input_path = file.get_optimal_target_path();
+    let repack_output_path = output_path.clone();
+    let repacked = spawn_blocking(move || {
+        repack_office_file(input_path.as_str(), repack_output_path.as_str())
+    })
+    .await
+    .unwrap();
+    match repacked {
+        Ok(_) => {
+            fm_list
+                .lock()
+                .unwrap()
+                .get_mut(file_idx)
+                .unwrap()
+                .compressed_file_path = output_path
+        }
+        Err(e) => println!(
+            "{} | Unable to recompress office media: {e:?}",
+            file.file_name
+        ),
+    }
+}
+
 pub async fn compress(fm_list: Arc<Mutex<Vec<FileManager>>>) -> Arc<Mutex<Vec<FileManager>>> {
     let mut files_clone = fm_list.lock().unwrap().clone();
     let mut thread_handlers = Vec::new();
 
     for (file_idx, file) in files_clone.iter_mut().enumerate() {
         match file.ext.clone().as_str() {
-            "pdf" => {
+            "pdf" if file.shrinks_pdf() => {
                 thread_handlers.push(spawn(compress_pdf(
                     Arc::new(file.clone()),
                     file_idx,
                     fm_list.clone(),
                 )));
             }
+            _ if file.compresses_office_media() => {
+                thread_handlers.push(spawn(compress_office(
+                    Arc::new(file.clone()),
+                    file_idx,
+                    fm_list.clone(),
+                )));
+            }
             _ => {}
         }
     }
@@ -0,0 +1,486 @@
+use std::{env, fmt, ops::RangeInclusive, str::FromStr};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Default, Debug)]
+pub struct ProcessingOptions {
+    // Recompress the images embedded inside exported pptx/docx/xlsx files and shrink PDFs
+    pub compress_office_media: bool,
+    // Kind of link file written for Workspace files which cannot be exported
+    pub placeholder: PlaceholderFormat,
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaceholderFormat {
+    // Page redirecting to the file, opens everywhere
+    #[default]
+    Html,
+    // Windows internet shortcut
+    Url,
+    // Linux desktop entry
+    Desktop,
+}
+
+impl PlaceholderFormat {
+    pub fn get_mime_type_and_ext(&self) -> (&'static str, &'static str) {
+        match self {
+            PlaceholderFormat::Html => ("text/html", "html"),
+            PlaceholderFormat::Url => ("application/internet-shortcut", "url"),
+            PlaceholderFormat::Desktop => ("application/x-desktop", "desktop"),
+        }
+    }
+
+    // Contents of a link file named `name` pointing at `link`
+    pub fn render(&self, name: &str, link: &str) -> String {
+        match self {
+            PlaceholderFormat::Html => {
+                let escape = |text: &str| {
+                    text.replace('&', "&amp;")
+                        .replace('<', "&lt;")
+                        .replace('>', "&gt;")
+                        .replace('"', "&quot;")
+                };
+                format!(
+                    "<!DOCTYPE html>\n\
+                     <html>\n\
+                     <head>\n\
+                     <meta charset=\"utf-8\">\n\
+                     <title>{name}</title>\n\
+                     <meta http-equiv=\"refresh\" content=\"0; url={link}\">\n\
+                     </head>\n\
+                     <body><a href=\"{link}\">{name}</a></body>\n\
+                     </html>\n",
+                    name = escape(name),
+                    link = escape(link),
+                )
+            }
+            PlaceholderFormat::Url => format!("[InternetShortcut]\r\nURL={}\r\n", link),
+            PlaceholderFormat::Desktop => format!(
+                "[Desktop Entry]\nType=Link\nName={}\nURL={}\nIcon=text-html\n",
+                name.replace(['\r', '\n'], " "),
+                link
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputMode {
+    // Every downloaded file inside a single archive
+    #[default]
+    #[serde(alias = "zip")]
+    Archive,
+    // Every downloaded PDF concatenated into a single PDF
+    Pdf,
+}
+
+// Where the Drive metadata of every file goes, such as its owners, description and properties
+#[derive(Clone, Copy, Default, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataMode {
+    #[default]
+    Omit,
+    // A `.meta.json` file next to every file
+    Sidecar,
+    // One line per file inside metadata.jsonl
+    Jsonl,
+}
+
+// What happens to entries with the same content as another entry of the archive
+#[derive(Clone, Copy, Default, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    #[default]
+    Keep,
+    // Stores the content once and lists the other paths in duplicates.txt
+    StoreOnce,
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Deserialize)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "zip")]
+    Zip,
+    #[serde(rename = "tar")]
+    Tar,
+    #[serde(rename = "tar.gz", alias = "tgz")]
+    TarGz,
+    #[serde(rename = "tar.zst", alias = "tzst")]
+    TarZst,
+}
+
+impl ArchiveFormat {
+    pub fn get_ext(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarZst => "tar.zst",
+        }
+    }
+
+    pub fn get_mime_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::TarGz => "application/gzip",
+            ArchiveFormat::TarZst => "application/zstd",
+        }
+    }
+
+    // Range of compression levels accepted along with the one used when none is requested
+    // A plain tar is never compressed
+    pub fn get_compression_levels(&self) -> Option<(RangeInclusive<i32>, i32)> {
+        match self {
+            ArchiveFormat::Zip | ArchiveFormat::TarGz => Some((0..=9, 6)),
+            ArchiveFormat::Tar => None,
+            ArchiveFormat::TarZst => Some((1..=22, 3)),
+        }
+    }
+}
+
+// Archive password which is never printed so that it cannot end up in the logs
+#[derive(Clone)]
+pub struct Password(String);
+
+impl Password {
+    pub fn new(password: String) -> Self {
+        Self(password)
+    }
+
+    pub fn expose(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Password(***)")
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct ArchiveOptions {
+    // Add manifest.csv next to the manifest.json every archive carries
+    pub manifest_csv: bool,
+    pub format: ArchiveFormat,
+    // Falls back to the default level of the format
+    pub compression_level: Option<i32>,
+    // Writes every zip entry uncompressed instead of only the already compressed ones
+    pub store_only: bool,
+    // Encrypts every entry of the zip with AES-256
+    pub password: Option<Password>,
+    // Splits the output into standalone archives of at most this many bytes, delivered inside a zip
+    pub max_part_size: Option<u64>,
+    pub duplicates: DuplicatePolicy,
+    pub metadata: MetadataMode,
+}
+
+impl ArchiveOptions {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(password) = self.password.as_ref() {
+            if password.expose().is_empty() {
+                bail!("Archive password cannot be empty");
+            }
+            if self.format != ArchiveFormat::Zip {
+                bail!("Only zip archives can be encrypted");
+            }
+        }
+        if self.store_only {
+            if self.format != ArchiveFormat::Zip {
+                bail!("Only zip archives can be store-only, tar is never compressed");
+            }
+            if self.compression_level.is_some() {
+                bail!("Store-only archives cannot have a compression level");
+            }
+        }
+        if self.max_part_size == Some(0) {
+            bail!("Part size has to be larger than 0 bytes");
+        }
+        if let Some(compression_level) = self.compression_level {
+            match self.format.get_compression_levels() {
+                Some((levels, _)) if levels.contains(&compression_level) => {}
+                Some((levels, _)) => bail!(
+                    "Compression level of {} must be between {} and {}",
+                    self.format.get_ext(),
+                    levels.start(),
+                    levels.end()
+                ),
+                None => bail!("{} archives are not compressed", self.format.get_ext()),
+            }
+        }
+        Ok(())
+    }
+
+    // Split archives are always delivered as a zip of the parts
+    pub fn get_output_format(&self) -> ArchiveFormat {
+        match self.max_part_size {
+            Some(_) => ArchiveFormat::Zip,
+            None => self.format,
+        }
+    }
+
+    pub fn get_compression_level(&self) -> i32 {
+        let default_level = self
+            .format
+            .get_compression_levels()
+            .map(|(_, default_level)| default_level)
+            .unwrap_or_default();
+        self.compression_level.unwrap_or(default_level)
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeOrder {
+    #[default]
+    Name,
+    Path,
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct MergeOptions {
+    pub order: MergeOrder,
+    // Prepend pages listing every merged file along with its starting page
+    pub table_of_contents: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ExportFormat {
+    Pdf,
+    Docx,
+    Odt,
+    Txt,
+    Html,
+    Epub,
+    Markdown,
+    Xlsx,
+    Csv,
+    Ods,
+    Tsv,
+    Pptx,
+    Odp,
+    Png,
+    Svg,
+    Jpeg,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format.trim().to_lowercase().as_str() {
+            "pdf" => Ok(ExportFormat::Pdf),
+            "docx" => Ok(ExportFormat::Docx),
+            "odt" => Ok(ExportFormat::Odt),
+            "txt" => Ok(ExportFormat::Txt),
+            "html" => Ok(ExportFormat::Html),
+            "epub" => Ok(ExportFormat::Epub),
+            "markdown" | "md" => Ok(ExportFormat::Markdown),
+            "xlsx" => Ok(ExportFormat::Xlsx),
+            "csv" => Ok(ExportFormat::Csv),
+            "ods" => Ok(ExportFormat::Ods),
+            "tsv" => Ok(ExportFormat::Tsv),
+            "pptx" => Ok(ExportFormat::Pptx),
+            "odp" => Ok(ExportFormat::Odp),
+            "png" => Ok(ExportFormat::Png),
+            "svg" => Ok(ExportFormat::Svg),
+            "jpeg" | "jpg" => Ok(ExportFormat::Jpeg),
+            _ => bail!("Unknown export format `{}`", format),
+        }
+    }
+}
+
+impl ExportFormat {
+    // MIME type requested from files.export along with the extension of the exported file
+    pub fn get_mime_type_and_ext(&self) -> (&'static str, &'static str) {
+        match self {
+            ExportFormat::Pdf => ("application/pdf", "pdf"),
+            ExportFormat::Docx => (
+                "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+                "docx",
+            ),
+            ExportFormat::Odt => ("application/vnd.oasis.opendocument.text", "odt"),
+            ExportFormat::Txt => ("text/plain", "txt"),
+            ExportFormat::Html => ("text/html", "html"),
+            ExportFormat::Epub => ("application/epub+zip", "epub"),
+            ExportFormat::Markdown => ("text/markdown", "md"),
+            ExportFormat::Xlsx => (
+                "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+                "xlsx",
+            ),
+            ExportFormat::Csv => ("text/csv", "csv"),
+            ExportFormat::Ods => ("application/x-vnd.oasis.opendocument.spreadsheet", "ods"),
+            ExportFormat::Tsv => ("text/tab-separated-values", "tsv"),
+            ExportFormat::Pptx => (
+                "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+                "pptx",
+            ),
+            ExportFormat::Odp => ("application/vnd.oasis.opendocument.presentation", "odp"),
+            ExportFormat::Png => ("image/png", "png"),
+            ExportFormat::Svg => ("image/svg+xml", "svg"),
+            ExportFormat::Jpeg => ("image/jpeg", "jpg"),
+        }
+    }
+
+    // Formats Drive can export each configurable Workspace type to
+    pub fn get_supported_formats(workspace_mime_type: &str) -> &'static [ExportFormat] {
+        match workspace_mime_type {
+            "application/vnd.google-apps.document" => &[
+                ExportFormat::Pdf,
+                ExportFormat::Docx,
+                ExportFormat::Odt,
+                ExportFormat::Txt,
+                ExportFormat::Html,
+                ExportFormat::Epub,
+                ExportFormat::Markdown,
+            ],
+            "application/vnd.google-apps.spreadsheet" => &[
+                ExportFormat::Xlsx,
+                ExportFormat::Csv,
+                ExportFormat::Ods,
+                ExportFormat::Pdf,
+                ExportFormat::Tsv,
+            ],
+            "application/vnd.google-apps.presentation" => &[
+                ExportFormat::Pptx,
+                ExportFormat::Pdf,
+                ExportFormat::Odp,
+                ExportFormat::Txt,
+            ],
+            "application/vnd.google-apps.drawing" => &[
+                ExportFormat::Pdf,
+                ExportFormat::Png,
+                ExportFormat::Svg,
+                ExportFormat::Jpeg,
+            ],
+            _ => &[],
+        }
+    }
+
+    // Parses a comma separated list of formats such as `pdf,docx`
+    pub fn parse_list(formats: &str) -> Result<Vec<ExportFormat>> {
+        let formats = formats
+            .split(',')
+            .filter(|format| !format.trim().is_empty())
+            .map(ExportFormat::from_str)
+            .collect::<Result<Vec<_>>>()?;
+
+        if formats.is_empty() {
+            bail!("At least one export format is required");
+        }
+        Ok(formats)
+    }
+}
+
+// Formats every configurable Workspace type gets exported to
+// Listing more than one format exports the same file once per format
+#[derive(Clone, Debug)]
+pub struct ExportFormats {
+    pub document: Vec<ExportFormat>,
+    pub spreadsheet: Vec<ExportFormat>,
+    pub presentation: Vec<ExportFormat>,
+    pub drawing: Vec<ExportFormat>,
+}
+
+impl Default for ExportFormats {
+    fn default() -> Self {
+        Self {
+            document: vec![ExportFormat::Pdf],
+            spreadsheet: vec![ExportFormat::Xlsx],
+            presentation: vec![ExportFormat::Pptx],
+            drawing: vec![ExportFormat::Pdf],
+        }
+    }
+}
+
+impl ExportFormats {
+    // Server wide defaults which can be overridden through the env, eg. DOCUMENT_EXPORT_FORMATS=pdf,docx
+    pub fn from_env() -> Result<Self> {
+        let mut export_formats = Self::default();
+        for (env_name, formats) in [
+            ("DOCUMENT_EXPORT_FORMATS", &mut export_formats.document),
+            (
+                "SPREADSHEET_EXPORT_FORMATS",
+                &mut export_formats.spreadsheet,
+            ),
+            (
+                "PRESENTATION_EXPORT_FORMATS",
+                &mut export_formats.presentation,
+            ),
+            ("DRAWING_EXPORT_FORMATS", &mut export_formats.drawing),
+        ] {
+            if let Ok(env_formats) = env::var(env_name) {
+                *formats = ExportFormat::parse_list(env_formats.as_str())?;
+            }
+        }
+
+        export_formats.validate()?;
+        Ok(export_formats)
+    }
+
+    pub fn get(&self, workspace_mime_type: &str) -> &[ExportFormat] {
+        match workspace_mime_type {
+            "application/vnd.google-apps.document" => &self.document,
+            "application/vnd.google-apps.spreadsheet" => &self.spreadsheet,
+            "application/vnd.google-apps.presentation" => &self.presentation,
+            "application/vnd.google-apps.drawing" => &self.drawing,
+            _ => &[],
+        }
+    }
+
+    // Keeps a single format per type, preferring `format` wherever Drive supports it
+    pub fn with_single_format(&self, format: Option<ExportFormat>) -> Self {
+        let pick = |workspace_mime_type: &str| {
+            let supported_formats = ExportFormat::get_supported_formats(workspace_mime_type);
+            format
+                .filter(|format| supported_formats.contains(format))
+                .or_else(|| self.get(workspace_mime_type).first().copied())
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+
+        Self {
+            document: pick("application/vnd.google-apps.document"),
+            spreadsheet: pick("application/vnd.google-apps.spreadsheet"),
+            presentation: pick("application/vnd.google-apps.presentation"),
+            drawing: pick("application/vnd.google-apps.drawing"),
+        }
+    }
+
+    // Ensures every type is only exported to formats Drive supports for it
+    pub fn validate(&self) -> Result<()> {
+        for workspace_mime_type in [
+            "application/vnd.google-apps.document",
+            "application/vnd.google-apps.spreadsheet",
+            "application/vnd.google-apps.presentation",
+            "application/vnd.google-apps.drawing",
+        ] {
+            let supported_formats = ExportFormat::get_supported_formats(workspace_mime_type);
+            for format in self.get(workspace_mime_type) {
+                if !supported_formats.contains(format) {
+                    bail!(
+                        "{} cannot be exported as {}",
+                        workspace_mime_type,
+                        format.get_mime_type_and_ext().1
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// A file which was left out of the output because it could not be downloaded
+#[derive(Clone, Debug, Serialize)]
+pub struct DownloadError {
+    pub file_id: String,
+    pub name: String,
+    pub error: String,
+    // Roster row the file was linked from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub row: Option<usize>,
+}
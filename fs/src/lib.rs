@@ -1,16 +1,27 @@
 use std::{
-    fs,
+    collections::HashSet,
+    fs, io,
+    path::Path,
     sync::{Arc, Mutex},
 };
 
-use anyhow::{Ok, Result};
+use anyhow::{bail, Ok, Result};
 use cache::CacheManager;
-use compression::compress;
-use google_drive3::{api::File, hyper::body::Bytes};
+use compression::{compress, OFFICE_EXTENSIONS};
+use google_drive3::{
+    api::File,
+    hyper::body::{Body, Bytes, HttpBody},
+};
+use interface::{ExportFormat, ExportFormats, ProcessingOptions};
+use md5::{Digest, Md5};
+use tokio::io::AsyncWriteExt;
 
 pub mod archive;
 pub mod cache;
 pub mod compression;
+pub mod interface;
+pub mod manifest;
+pub mod merge;
 
 pub static CACHE_KEY_STORE_PATH: &str = "tmp/.cache/keyStore.csv";
 pub static CACHE_FILES_PATH: &str = "tmp/.cache/files";
@@ -18,38 +29,74 @@ pub static TMP_BASE_PATH: &str = "tmp";
 pub static TMP_FILES_UNCOMPRESSED_BASE_PATH: &str = "tmp/files";
 pub static TMP_FILES_COMPRESSED_BASE_PATH: &str = "tmp/compressed";
 pub static TMP_FILES_OUTPUT_BASE_PATH: &str = "tmp/output";
+pub static TMP_FILES_STREAM_BASE_PATH: &str = "tmp/stream";
 pub static TMP_CACHE_PATH: &str = "tmp/.cache";
 
 #[derive(Clone)]
 pub struct FileManager {
     pub file: File,
     pub base_path: String,
+    // Path of the containing folder relative to the downloaded root
+    pub folder_path: String,
     pub file_name: String,
     pub mime_type: String,
     pub ext: String,
+    // Format Workspace files are exported to, None for every other file
+    pub export_format: Option<ExportFormat>,
     pub compressed_file_path: String,
     pub cache_manager: Arc<Mutex<CacheManager>>,
     pub cached_path: String,
     pub is_cached: bool,
+    // Link file standing in for a Workspace file which cannot be exported
+    pub is_placeholder: bool,
+    pub processing: ProcessingOptions,
+    // Path inside the output when it has to differ from the one on disk
+    pub renamed_path: Option<String>,
 }
 
 impl FileManager {
-    pub fn new(file: File, cache_manager: Arc<Mutex<CacheManager>>, base_path: String) -> Self {
-        let (mime_type, ext) = Self::get_mime_type_and_ext(file.clone());
+    pub fn new(
+        file: File,
+        cache_manager: Arc<Mutex<CacheManager>>,
+        base_path: String,
+        folder_path: String,
+        export_format: Option<ExportFormat>,
+        processing: ProcessingOptions,
+    ) -> Self {
+        let export_format = Self::resolve_export_format(&file, export_format);
+        let (mut mime_type, mut ext) = Self::get_mime_type_and_ext(file.clone(), export_format);
+
+        let is_placeholder = mime_type.is_empty();
+        if is_placeholder {
+            let (placeholder_mime_type, placeholder_ext) =
+                processing.placeholder.get_mime_type_and_ext();
+            (mime_type, ext) = (
+                placeholder_mime_type.to_string(),
+                placeholder_ext.to_string(),
+            );
+        }
 
         let mut file_manager = Self {
             file: file.clone(),
             base_path,
+            folder_path,
             file_name: Self::get_file_name(file.clone(), ext.clone()),
             mime_type,
             ext,
+            export_format,
             compressed_file_path: String::new(),
             cache_manager,
             is_cached: false,
             cached_path: String::new(),
+            is_placeholder,
+            processing,
+            renamed_path: None,
         };
 
-        file_manager.sync_cache(file);
+        // Placeholders are cheap to write so they never go through the cache
+        if !is_placeholder {
+            file_manager.sync_cache(file);
+        }
 
         file_manager
     }
@@ -62,17 +109,21 @@ impl FileManager {
         }
 
         let rm_unwrap = revision_map.unwrap();
-        let default_val = String::from("");
-        let cached_file_path = rm_unwrap
-            .get(self.get_file_revision_id().as_str())
-            .unwrap_or(&default_val);
+        let cache_entry = match rm_unwrap.get(self.get_cache_key().as_str()) {
+            Some(cache_entry) => cache_entry,
+            None => return,
+        };
 
-        if !cached_file_path.is_empty() {
-            self.cached_path = cached_file_path.to_string();
-            self.is_cached = true
+        // Revalidate against the checksum Drive currently reports for the file
+        if file.md5_checksum.is_some() && cache_entry.md5_checksum != file.md5_checksum {
+            return;
         }
 
-        return;
+        // A truncated or corrupted cache file is downloaded again instead of being served
+        if !cache_entry.path.is_empty() && cache_entry.is_intact() {
+            self.cached_path = cache_entry.path.clone();
+            self.is_cached = true
+        }
     }
 
     // Creates the file name with accurate extension
@@ -88,28 +139,30 @@ impl FileManager {
         // Add the appropriate extension
         file_name_parts.push(ext.as_str());
 
-        return file_name_parts.join(".").to_string();
+        file_name_parts.join(".").to_string()
+    }
+
+    // Configurable Workspace types get exported to the requested format or the default one
+    fn resolve_export_format(
+        file: &File,
+        export_format: Option<ExportFormat>,
+    ) -> Option<ExportFormat> {
+        let mime_type = file.mime_type.clone().unwrap();
+        export_format
+            .or_else(|| ExportFormats::default().get(&mime_type).first().copied())
+            .filter(|format| ExportFormat::get_supported_formats(&mime_type).contains(format))
     }
 
     // Calculates what should be the mime_type based on the documentation
-    fn get_mime_type_and_ext(file: File) -> (String, String) {
-        match file.mime_type.clone().unwrap().as_str() {
-            "application/vnd.google-apps.spreadsheet" => (
-                String::from("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
-                String::from("xlsx"),
-            ),
-            "application/vnd.google-apps.document" => {
-                (String::from("application/pdf"), String::from("pdf"))
-            }
-            "application/vnd.google-apps.presentation" => (
-                String::from(
-                    "application/vnd.openxmlformats-officedocument.presentationml.presentation",
-                ),
-                String::from("pptx"),
-            ),
-            "application/vnd.google-apps.drawing" => {
-                (String::from("application/pdf"), String::from("pdf"))
-            }
+    fn get_mime_type_and_ext(file: File, export_format: Option<ExportFormat>) -> (String, String) {
+        let mime_type = file.mime_type.clone().unwrap();
+
+        if let Some(format) = export_format {
+            let (export_mime_type, ext) = format.get_mime_type_and_ext();
+            return (export_mime_type.to_string(), ext.to_string());
+        }
+
+        match mime_type.as_str() {
             "application/vnd.google-apps.script" => (
                 String::from("application/vnd.google-apps.script+json"),
                 String::from("json"),
@@ -123,7 +176,11 @@ impl FileManager {
 
     // Returns the original target path of the file
     pub fn get_target_path(&self) -> String {
-        format!("{}/{}", self.base_path, self.file_name)
+        if self.folder_path.is_empty() {
+            format!("{}/{}", self.base_path, self.file_name)
+        } else {
+            format!("{}/{}/{}", self.base_path, self.folder_path, self.file_name)
+        }
     }
 
     // Calculates what should be the location of the compressed files
@@ -150,30 +207,247 @@ impl FileManager {
 
     // Checks the target path and the base path and returns the relative path
     pub fn get_relative_path(&self) -> String {
+        if let Some(renamed_path) = self.renamed_path.as_ref() {
+            return renamed_path.clone();
+        }
+
         let target_path = self.get_target_path();
         let base_path_parts = self.base_path.split("/").collect::<Vec<&str>>();
         let target_path_parts = target_path.split("/").collect::<Vec<&str>>();
 
         let mut relative_path_parts = Vec::new();
-        for path_idx in base_path_parts.len()..target_path_parts.len() {
-            relative_path_parts.push(target_path_parts[path_idx]);
+        for path_part in target_path_parts.iter().skip(base_path_parts.len()) {
+            relative_path_parts.push(*path_part);
+        }
+
+        relative_path_parts.join("/")
+    }
+
+    // Gives every file sharing its relative path with another one a name like `name (1).ext`
+    // Files are ordered by path and ID first so that the same files always get the same names
+    pub fn deduplicate_relative_paths(files: &mut [FileManager]) {
+        files.sort_by_key(|file| {
+            (
+                file.get_relative_path(),
+                file.file.id.clone().unwrap_or_default(),
+            )
+        });
+
+        let relative_paths = files
+            .iter()
+            .map(|file| file.get_relative_path())
+            .collect::<Vec<_>>();
+        let mut taken_paths = relative_paths.iter().cloned().collect::<HashSet<_>>();
+        for file_idx in 1..files.len() {
+            let relative_path = relative_paths[file_idx].clone();
+            if relative_path != relative_paths[file_idx - 1] {
+                continue;
+            }
+
+            let (folder, name) = match relative_path.rsplit_once('/') {
+                Some((folder, name)) => (format!("{}/", folder), name.to_string()),
+                None => (String::new(), relative_path.clone()),
+            };
+            let (stem, ext) = match name.rsplit_once('.') {
+                Some((stem, ext)) if !stem.is_empty() => (stem.to_string(), format!(".{}", ext)),
+                _ => (name.clone(), String::new()),
+            };
+
+            let mut copy_idx = 1;
+            let mut renamed_path = format!("{}{} ({}){}", folder, stem, copy_idx, ext);
+            while taken_paths.contains(&renamed_path) {
+                copy_idx += 1;
+                renamed_path = format!("{}{} ({}){}", folder, stem, copy_idx, ext);
+            }
+            taken_paths.insert(renamed_path.clone());
+            files[file_idx].renamed_path = Some(renamed_path);
+        }
+    }
+
+    // Files sharing this key have the same content, placeholders are never shared as they carry their own name
+    pub fn get_content_key(&self) -> Option<String> {
+        if self.is_placeholder {
+            return None;
+        }
+        match self.file.md5_checksum.as_ref() {
+            Some(md5_checksum) if self.export_format.is_none() => Some(format!(
+                "md5:{}{}",
+                md5_checksum,
+                if self.is_processed() { "-media" } else { "" }
+            )),
+            _ => Some(format!(
+                "id:{}_{}",
+                self.file.id.clone().unwrap_or_default(),
+                self.get_cache_key()
+            )),
+        }
+    }
+
+    fn create_target_dirs(&self) {
+        for path in [self.get_target_path(), self.get_compressed_target_path()] {
+            fs::create_dir_all(Path::new(path.as_str()).parent().unwrap()).unwrap();
+        }
+    }
+
+    async fn compress_written_file(&mut self) {
+        let compressed = compress(Arc::new(Mutex::new(vec![self.clone()]))).await;
+        self.compressed_file_path = compressed.lock().unwrap()[0].compressed_file_path.clone();
+    }
+
+    fn check_content(&self, size: u64, content_md5: String) -> Result<()> {
+        if let Some(expected_size) = self.file.size {
+            if size as i64 != expected_size {
+                bail!("Expected {} bytes but received {}", expected_size, size);
+            }
+        }
+        if let Some(md5_checksum) = self.file.md5_checksum.as_ref() {
+            if !content_md5.eq_ignore_ascii_case(md5_checksum) {
+                bail!("Expected md5 {} but received {}", md5_checksum, content_md5);
+            }
         }
+        Ok(())
+    }
+
+    // Checks downloaded content against the size and md5Checksum reported by Drive
+    pub fn verify_content(&self, content: &Bytes) -> Result<()> {
+        self.check_content(content.len() as u64, format!("{:x}", Md5::digest(content)))
+    }
+
+    // Same as verify_content for content which was written to the target path bit by bit
+    pub fn verify_written_file(&self) -> Result<()> {
+        let mut hasher = Md5::new();
+        let size = io::copy(&mut fs::File::open(self.get_target_path())?, &mut hasher)?;
+        self.check_content(size, format!("{:x}", hasher.finalize()))
+    }
+
+    // Opens the target file so the caller can write the content in chunks
+    // finish_written_file has to be called once everything was written
+    pub async fn create_target_file(&self) -> Result<tokio::fs::File> {
+        self.create_target_dirs();
+        Ok(tokio::fs::File::create(self.get_target_path()).await?)
+    }
 
-        return relative_path_parts.join("/");
+    pub async fn finish_written_file(&mut self) -> Result<()> {
+        self.verify_written_file()?;
+        self.compress_written_file().await;
+        Ok(())
     }
 
     // Write file to fs
-    pub async fn write_file(&self, content: Bytes) -> Result<()> {
-        fs::create_dir_all(self.base_path.as_str()).unwrap();
+    pub async fn write_file(&mut self, content: Bytes) -> Result<()> {
+        self.create_target_dirs();
         fs::write(self.get_target_path(), &content).unwrap();
-        compress(Arc::new(Mutex::new(vec![self.clone()]))).await;
+        self.compress_written_file().await;
+        Ok(())
+    }
+
+    // Streams the response body to fs without holding the whole file in memory
+    pub async fn write_body(&mut self, mut body: Body) -> Result<()> {
+        self.create_target_dirs();
+        let mut file = tokio::fs::File::create(self.get_target_path()).await?;
+        while let Some(chunk) = body.data().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.flush().await?;
+        self.compress_written_file().await;
         Ok(())
     }
 
+    // Takes over the content another file of the same request already downloaded
+    pub async fn copy_from(&mut self, source: &FileManager) -> Result<()> {
+        self.create_target_dirs();
+        tokio::fs::copy(source.get_target_path(), self.get_target_path()).await?;
+        if !source.compressed_file_path.is_empty() {
+            tokio::fs::copy(
+                source.compressed_file_path.as_str(),
+                self.get_compressed_target_path(),
+            )
+            .await?;
+            self.compressed_file_path = self.get_compressed_target_path();
+        }
+        Ok(())
+    }
+
+    // Writes a link file pointing at the file on Drive
+    pub async fn write_placeholder(&mut self) -> Result<()> {
+        let link = self.file.web_view_link.clone().unwrap_or(format!(
+            "https://drive.google.com/open?id={}",
+            self.file.id.clone().unwrap()
+        ));
+        let content = self
+            .processing
+            .placeholder
+            .render(self.file.name.clone().unwrap().as_str(), link.as_str());
+
+        self.write_file(Bytes::from(content)).await
+    }
+
     pub fn get_file_revision_id(&self) -> String {
         self.file
             .head_revision_id
             .clone()
             .unwrap_or("_".to_string())
     }
+
+    fn get_export_version(&self) -> String {
+        match self.get_content_version() {
+            version if version.is_empty() => "_".to_string(),
+            version => version,
+        }
+    }
+
+    // Whether the embedded media of this office file should be recompressed
+    pub fn compresses_office_media(&self) -> bool {
+        self.processing.compress_office_media && OFFICE_EXTENSIONS.contains(&self.ext.as_str())
+    }
+
+    // PDFs are only shrunk when asked for as ghostscript is lossy
+    pub fn shrinks_pdf(&self) -> bool {
+        self.processing.compress_office_media && self.ext == "pdf"
+    }
+
+    // Whether the written file is replaced by a processed variant
+    pub fn is_processed(&self) -> bool {
+        self.compresses_office_media() || self.shrinks_pdf()
+    }
+
+    // Key of this file inside the revision map of the cache
+    // Every export format and processed variant of the same revision is cached separately
+    pub fn get_cache_key(&self) -> String {
+        // Workspace files have no revision, their version changes with every edit instead
+        let mut cache_key = match self.export_format {
+            Some(_) => format!("{}-{}", self.get_export_version(), self.ext),
+            None => self.get_file_revision_id(),
+        };
+        if self.is_processed() {
+            cache_key = format!("{}-media", cache_key);
+        }
+        cache_key
+    }
+
+    // Changes whenever the content on Drive changes
+    // Workspace files have neither a revision nor a checksum but every edit bumps their version
+    pub fn get_content_version(&self) -> String {
+        self.file
+            .head_revision_id
+            .clone()
+            .or(self.file.md5_checksum.clone())
+            .or(self.file.version.map(|version| version.to_string()))
+            .or(self
+                .file
+                .modified_time
+                .map(|modified_time| modified_time.to_rfc3339()))
+            .unwrap_or_default()
+    }
+
+    // Identifies what this file adds to an output, used to build ETags of whole outputs
+    pub fn get_version_key(&self) -> String {
+        format!(
+            "{}/{}/{}/{}",
+            self.file.id.clone().unwrap_or_default(),
+            self.get_content_version(),
+            self.get_cache_key(),
+            self.get_relative_path()
+        )
+    }
 }
@@ -0,0 +1,164 @@
+use std::{collections::BTreeMap, fs, io};
+
+use anyhow::Result;
+use csv::Writer;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::FileManager;
+
+pub static MANIFEST_JSON_NAME: &str = "manifest.json";
+pub static MANIFEST_CSV_NAME: &str = "manifest.csv";
+pub static METADATA_JSONL_NAME: &str = "metadata.jsonl";
+pub static METADATA_SIDECAR_EXT: &str = "meta.json";
+
+// Describes a single archive entry so that archives can be reconciled against Drive
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ManifestEntry {
+    pub file_id: String,
+    pub name: String,
+    pub archive_path: String,
+    pub drive_mime_type: String,
+    pub mime_type: String,
+    pub revision_id: Option<String>,
+    // RFC 3339, missing from manifests written before it was added
+    #[serde(default)]
+    pub modified_time: Option<String>,
+    pub md5_checksum: Option<String>,
+    pub sha256: String,
+    // Size as downloaded from Drive, unknown when served from the cache
+    pub original_size: Option<u64>,
+    pub size: u64,
+    pub is_cached: bool,
+    pub is_placeholder: bool,
+    pub web_view_link: Option<String>,
+    // Archive path of the entry holding the same content, set when this one was left out
+    #[serde(default)]
+    pub duplicate_of: Option<String>,
+}
+
+impl ManifestEntry {
+    pub fn new(file: &FileManager) -> Result<Self> {
+        let path = file.get_optimal_target_path();
+
+        let mut hasher = Sha256::new();
+        io::copy(&mut fs::File::open(path.as_str())?, &mut hasher)?;
+
+        Ok(Self {
+            file_id: file.file.id.clone().unwrap_or_default(),
+            name: file.file.name.clone().unwrap_or_default(),
+            archive_path: file.get_relative_path(),
+            drive_mime_type: file.file.mime_type.clone().unwrap_or_default(),
+            mime_type: file.mime_type.clone(),
+            revision_id: file.file.head_revision_id.clone(),
+            modified_time: file
+                .file
+                .modified_time
+                .map(|modified_time| modified_time.to_rfc3339()),
+            md5_checksum: file.file.md5_checksum.clone(),
+            sha256: format!("{:x}", hasher.finalize()),
+            original_size: fs::metadata(file.get_target_path())
+                .ok()
+                .map(|metadata| metadata.len()),
+            size: fs::metadata(path)?.len(),
+            is_cached: file.is_cached,
+            is_placeholder: file.is_placeholder,
+            web_view_link: file.file.web_view_link.clone(),
+            duplicate_of: None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MetadataOwner {
+    pub display_name: Option<String>,
+    pub email_address: Option<String>,
+}
+
+// Drive metadata of an archive entry which the file itself cannot carry
+#[derive(Clone, Debug, Serialize)]
+pub struct FileMetadata {
+    pub archive_path: String,
+    pub file_id: String,
+    pub name: String,
+    pub mime_type: String,
+    pub modified_time: Option<String>,
+    pub owners: Vec<MetadataOwner>,
+    pub description: Option<String>,
+    pub properties: BTreeMap<String, String>,
+    pub web_view_link: Option<String>,
+}
+
+impl FileMetadata {
+    pub fn new(file: &FileManager) -> Self {
+        Self {
+            archive_path: file.get_relative_path(),
+            file_id: file.file.id.clone().unwrap_or_default(),
+            name: file.file.name.clone().unwrap_or_default(),
+            mime_type: file.file.mime_type.clone().unwrap_or_default(),
+            modified_time: file
+                .file
+                .modified_time
+                .map(|modified_time| modified_time.to_rfc3339()),
+            owners: file
+                .file
+                .owners
+                .iter()
+                .flatten()
+                .map(|owner| MetadataOwner {
+                    display_name: owner.display_name.clone(),
+                    email_address: owner.email_address.clone(),
+                })
+                .collect(),
+            description: file.file.description.clone(),
+            properties: file
+                .file
+                .properties
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .collect(),
+            web_view_link: file.file.web_view_link.clone(),
+        }
+    }
+}
+
+fn get_manifest_entry(file: &FileManager, duplicate_of: Option<String>) -> Option<ManifestEntry> {
+    match ManifestEntry::new(file) {
+        Ok(entry) => Some(ManifestEntry {
+            duplicate_of,
+            ..entry
+        }),
+        Err(e) => {
+            println!("{} | Unable to add to manifest: {e:?}", file.file_name);
+            None
+        }
+    }
+}
+
+pub fn get_manifest(files: &[FileManager]) -> Vec<ManifestEntry> {
+    files
+        .iter()
+        .filter_map(|file| get_manifest_entry(file, None))
+        .collect()
+}
+
+// Duplicates are not inside the archive, their entries point at the stored file instead
+pub fn get_duplicates_manifest(duplicates: &[(FileManager, String)]) -> Vec<ManifestEntry> {
+    duplicates
+        .iter()
+        .filter_map(|(file, stored_path)| get_manifest_entry(file, Some(stored_path.clone())))
+        .collect()
+}
+
+pub fn manifest_to_json(manifest: &[ManifestEntry]) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec_pretty(manifest)?)
+}
+
+pub fn manifest_to_csv(manifest: &[ManifestEntry]) -> Result<Vec<u8>> {
+    let mut wtr = Writer::from_writer(vec![]);
+    for entry in manifest {
+        wtr.serialize(entry)?;
+    }
+    Ok(wtr.into_inner()?)
+}
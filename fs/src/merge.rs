@@ -0,0 +1,312 @@
+use anyhow::{anyhow, bail, Result};
+use lopdf::{
+    content::{Content, Operation},
+    dictionary, Document, Object, ObjectId, Stream, StringFormat,
+};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    interface::{MergeOptions, MergeOrder},
+    FileManager,
+};
+
+// A4 in PDF points
+static PAGE_WIDTH: i64 = 595;
+static PAGE_HEIGHT: i64 = 842;
+static TOC_MARGIN: i64 = 50;
+static TOC_LINE_HEIGHT: i64 = 18;
+static TOC_FONT_SIZE: i64 = 11;
+static TOC_ENTRIES_PER_PAGE: usize = 38;
+static TOC_TITLE_MAX_CHARS: usize = 80;
+
+struct MergedEntry {
+    title: String,
+    first_page: ObjectId,
+    // Page number of the first page counted from the first merged document
+    page_offset: usize,
+}
+
+// PDF text strings are UTF-16BE prefixed with a byte order mark
+fn text_string(text: &str) -> Object {
+    let mut bytes = vec![0xFE, 0xFF];
+    for unit in text.encode_utf16() {
+        bytes.extend(unit.to_be_bytes());
+    }
+    Object::String(bytes, StringFormat::Hexadecimal)
+}
+
+// The standard fonts only cover WinAnsiEncoding, every other character is written as its code point
+// The outline and the link annotations carry the original title
+fn escape_toc_text(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            let encoded = Document::encode_text(Some("WinAnsiEncoding"), c.to_string().as_str());
+            if encoded.is_empty() {
+                format!("<U+{:04X}>", c as u32)
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+fn get_title(file: &FileManager) -> String {
+    match file.file_name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem.to_string(),
+        _ => file.file_name.clone(),
+    }
+}
+
+fn sort_files(files: &mut [FileManager], order: MergeOrder) {
+    match order {
+        MergeOrder::Name => files.sort_by_key(|file| file.file_name.to_lowercase()),
+        MergeOrder::Path => files.sort_by_key(|file| file.get_relative_path().to_lowercase()),
+    }
+}
+
+// Moves every object of the document into the merged one and hangs its page tree below the merged root
+// Returns the first page along with the number of pages appended
+fn append_document(
+    merged: &mut Document,
+    pages_id: ObjectId,
+    mut document: Document,
+) -> Result<(ObjectId, ObjectId, usize)> {
+    if document.is_encrypted() {
+        bail!("Encrypted PDFs cannot be merged");
+    }
+
+    document.renumber_objects_with(merged.max_id + 1);
+    merged.max_id = merged.max_id.max(document.max_id);
+
+    let catalog_id = document.trailer.get(b"Root")?.as_reference()?;
+    let root_pages_id = document.catalog()?.get(b"Pages")?.as_reference()?;
+    let pages = document.get_pages();
+    let first_page = *pages
+        .values()
+        .next()
+        .ok_or_else(|| anyhow!("PDF does not have any pages"))?;
+
+    // Keeping the original page tree intact preserves attributes inherited from it
+    document
+        .get_dictionary_mut(root_pages_id)?
+        .set("Parent", pages_id);
+    document.objects.remove(&catalog_id);
+    merged.objects.extend(document.objects);
+
+    Ok((root_pages_id, first_page, pages.len()))
+}
+
+fn add_table_of_contents(
+    merged: &mut Document,
+    pages_id: ObjectId,
+    entries: &[MergedEntry],
+    toc_page_count: usize,
+) -> Result<Vec<Object>> {
+    let font_id = merged.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => "Helvetica",
+        "Encoding" => "WinAnsiEncoding",
+    });
+    let mut toc_pages = Vec::new();
+
+    for (page_idx, chunk) in entries.chunks(TOC_ENTRIES_PER_PAGE).enumerate() {
+        let mut operations = Vec::new();
+        let mut annotations: Vec<Object> = Vec::new();
+        let mut y = PAGE_HEIGHT - TOC_MARGIN;
+
+        let mut write_text = |x: i64, y: i64, size: i64, text: &str| {
+            operations.push(Operation::new("BT", vec![]));
+            operations.push(Operation::new("Tf", vec!["F1".into(), size.into()]));
+            operations.push(Operation::new("Td", vec![x.into(), y.into()]));
+            operations.push(Operation::new(
+                "Tj",
+                vec![Object::string_literal(Document::encode_text(
+                    Some("WinAnsiEncoding"),
+                    text,
+                ))],
+            ));
+            operations.push(Operation::new("ET", vec![]));
+        };
+
+        if page_idx == 0 {
+            write_text(TOC_MARGIN, y, 16, "Table of Contents");
+            y -= 2 * TOC_LINE_HEIGHT;
+        }
+
+        for entry in chunk {
+            let mut title = escape_toc_text(entry.title.as_str());
+            if title.chars().count() > TOC_TITLE_MAX_CHARS {
+                title = title
+                    .chars()
+                    .take(TOC_TITLE_MAX_CHARS - 3)
+                    .collect::<String>()
+                    + "...";
+            }
+            let page_number = toc_page_count + entry.page_offset + 1;
+
+            write_text(TOC_MARGIN, y, TOC_FONT_SIZE, title.as_str());
+            write_text(
+                PAGE_WIDTH - TOC_MARGIN - 30,
+                y,
+                TOC_FONT_SIZE,
+                page_number.to_string().as_str(),
+            );
+
+            // Make the whole line clickable
+            annotations.push(
+                merged
+                    .add_object(dictionary! {
+                        "Type" => "Annot",
+                        "Subtype" => "Link",
+                        "Rect" => vec![
+                            TOC_MARGIN.into(),
+                            (y - 4).into(),
+                            (PAGE_WIDTH - TOC_MARGIN).into(),
+                            (y + TOC_LINE_HEIGHT - 4).into(),
+                        ],
+                        "Border" => vec![0.into(), 0.into(), 0.into()],
+                        "Contents" => text_string(entry.title.as_str()),
+                        "Dest" => vec![entry.first_page.into(), "Fit".into()],
+                    })
+                    .into(),
+            );
+            y -= TOC_LINE_HEIGHT;
+        }
+
+        let content_id = merged.add_object(Stream::new(
+            dictionary! {},
+            Content { operations }.encode()?,
+        ));
+        let page_id = merged.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content_id,
+            "Resources" => dictionary! {
+                "Font" => dictionary! {
+                    "F1" => font_id,
+                },
+            },
+            "MediaBox" => vec![0.into(), 0.into(), PAGE_WIDTH.into(), PAGE_HEIGHT.into()],
+            "Annots" => annotations,
+        });
+        toc_pages.push(page_id.into());
+    }
+
+    Ok(toc_pages)
+}
+
+// One top level bookmark per merged file
+fn add_outline(merged: &mut Document, entries: &[MergedEntry]) -> ObjectId {
+    let outlines_id = merged.new_object_id();
+    let item_ids = entries
+        .iter()
+        .map(|_| merged.new_object_id())
+        .collect::<Vec<_>>();
+
+    for (entry_idx, entry) in entries.iter().enumerate() {
+        let mut item = dictionary! {
+            "Title" => text_string(entry.title.as_str()),
+            "Parent" => outlines_id,
+            "Dest" => vec![entry.first_page.into(), "Fit".into()],
+        };
+        if entry_idx > 0 {
+            item.set("Prev", item_ids[entry_idx - 1]);
+        }
+        if entry_idx + 1 < item_ids.len() {
+            item.set("Next", item_ids[entry_idx + 1]);
+        }
+        merged.objects.insert(item_ids[entry_idx], item.into());
+    }
+
+    merged.objects.insert(
+        outlines_id,
+        dictionary! {
+            "Type" => "Outlines",
+            "First" => item_ids[0],
+            "Last" => item_ids[item_ids.len() - 1],
+            "Count" => item_ids.len() as i64,
+        }
+        .into(),
+    );
+
+    outlines_id
+}
+
+// Loading and saving every PDF is blocking work so it stays off the runtime
+pub async fn merge_pdf(
+    files: Vec<FileManager>,
+    options: MergeOptions,
+    output_path: &str,
+) -> Result<()> {
+    let output_path = output_path.to_string();
+    spawn_blocking(move || write_merged_pdf(files, options, output_path.as_str())).await?
+}
+
+fn write_merged_pdf(
+    mut files: Vec<FileManager>,
+    options: MergeOptions,
+    output_path: &str,
+) -> Result<()> {
+    files.retain(|file| file.mime_type == "application/pdf");
+    sort_files(&mut files, options.order);
+
+    let mut merged = Document::with_version("1.5");
+    let pages_id = merged.new_object_id();
+    let mut entries = Vec::new();
+    let mut document_pages = Vec::new();
+    let mut page_count = 0;
+
+    for file in files {
+        let appended = Document::load(file.get_optimal_target_path())
+            .map_err(anyhow::Error::from)
+            .and_then(|document| append_document(&mut merged, pages_id, document));
+
+        match appended {
+            Ok((root_pages_id, first_page, document_page_count)) => {
+                entries.push(MergedEntry {
+                    title: get_title(&file),
+                    first_page,
+                    page_offset: page_count,
+                });
+                document_pages.push(Object::Reference(root_pages_id));
+                page_count += document_page_count;
+            }
+            Err(e) => println!("{} | Skipping PDF while merging: {e:?}", file.file_name),
+        }
+    }
+
+    if entries.is_empty() {
+        bail!("No PDF files to merge");
+    }
+
+    let mut kids = Vec::new();
+    if options.table_of_contents {
+        let toc_page_count = entries.len().div_ceil(TOC_ENTRIES_PER_PAGE);
+        kids = add_table_of_contents(&mut merged, pages_id, &entries, toc_page_count)?;
+        page_count += kids.len();
+    }
+    kids.extend(document_pages);
+
+    merged.objects.insert(
+        pages_id,
+        dictionary! {
+            "Type" => "Pages",
+            "Kids" => kids,
+            "Count" => page_count as i64,
+        }
+        .into(),
+    );
+
+    let outlines_id = add_outline(&mut merged, &entries);
+    let catalog_id = merged.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+        "Outlines" => outlines_id,
+        "PageMode" => "UseOutlines",
+    });
+    merged.trailer.set("Root", catalog_id);
+
+    merged.save(output_path)?;
+    Ok(())
+}
@@ -2,9 +2,19 @@ extern crate google_drive3 as drive;
 
 mod oauth;
 
-use crate::routes::{download::download, shortcut::create_shortcut, upload::upload};
+use crate::routes::{
+    check::check,
+    download::{download, download_many},
+    file::file,
+    revisions::revisions,
+    roster::roster,
+    shortcut::create_shortcut,
+    stream::stream,
+    upload::upload,
+};
 use actix_web::{middleware, web::Data, App, HttpServer};
 use drive_manager::DriveManager;
+use fs::cache::CacheManager;
 use oauth::OAuthCredentialManager;
 use tracing::{event, Level};
 mod routes;
@@ -16,6 +26,7 @@ async fn main() -> std::io::Result<()> {
     let cred_manager = OAuthCredentialManager::default_initialize().await.unwrap();
     let drive_manager =
         DriveManager::new(cred_manager.connector.unwrap()).expect("Cant initialize drive manager");
+    actix_web::rt::spawn(CacheManager::verify_entries(drive_manager.cache.clone()));
 
     event!(
         Level::INFO,
@@ -26,7 +37,13 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .wrap(middleware::Compress::default())
             .app_data(Data::new(drive_manager.clone()))
+            .service(check)
             .service(download)
+            .service(download_many)
+            .service(file)
+            .service(revisions)
+            .service(roster)
+            .service(stream)
             .service(upload)
             .service(create_shortcut)
     })
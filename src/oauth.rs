@@ -93,7 +93,7 @@ impl OAuthCredentialManager {
     pub async fn default_initialize() -> Result<Self> {
         let mut cred_manager = Self::try_new_from_env_string(String::from("OAUTH_CREDENTIALS"));
 
-        if !cred_manager.is_ok() {
+        if cred_manager.is_err() {
             cred_manager = Self::try_new_from_env();
         }
 
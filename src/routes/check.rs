@@ -0,0 +1,31 @@
+use actix_web::{
+    post,
+    web::{Data, Json},
+    HttpResponse, Result,
+};
+use drive_manager::DriveManager;
+use serde::Deserialize;
+
+use super::interface::GenericResponse;
+
+#[derive(Deserialize)]
+pub struct CheckBody {
+    pub links: Vec<String>,
+}
+
+// Validates submitted links through their metadata without downloading them
+#[post("/check")]
+pub async fn check(
+    drive_manager: Data<DriveManager>,
+    body: Json<CheckBody>,
+) -> Result<HttpResponse> {
+    let links = body.into_inner().links;
+    if links.is_empty() {
+        return Ok(
+            HttpResponse::BadRequest().json(GenericResponse::<()>::error("No links to check"))
+        );
+    }
+
+    let link_checks = drive_manager.check_links(links).await;
+    Ok(HttpResponse::Ok().json(GenericResponse::ok("Checked links", Some(link_checks))))
+}
@@ -1,27 +1,380 @@
 use actix_files as afs;
-use actix_files::file_extension_to_mime;
 use actix_web::{
     get,
-    http::header::{ContentDisposition, ContentEncoding},
-    web::Data,
+    http::header::{ContentEncoding, DispositionType, HeaderValue, ETAG, IF_NONE_MATCH},
+    post,
+    web::{Data, Json, Query},
     HttpRequest, HttpResponse, Result,
 };
-use drive::{chrono::Utc, hyper::StatusCode};
-use drive_manager::DriveManager;
+use drive::chrono::Utc;
+use drive_manager::{
+    interface::{
+        DownloadFilters, DownloadLink, DownloadOptions, DownloadSession, IncrementalOptions,
+        Preview, ShortcutPolicy,
+    },
+    DriveManager,
+};
+use fs::{
+    interface::{
+        ArchiveFormat, ArchiveOptions, DuplicatePolicy, ExportFormat, ExportFormats, MergeOptions,
+        MergeOrder, MetadataMode, OutputMode, Password, PlaceholderFormat, ProcessingOptions,
+    },
+    manifest::ManifestEntry,
+};
+use serde::Deserialize;
+
+use super::interface::{get_content_disposition, matches_etag, GenericResponse};
+
+#[derive(Deserialize)]
+pub struct DownloadQuery {
+    #[serde(default)]
+    pub compress_office_media: bool,
+    #[serde(default)]
+    pub placeholder: PlaceholderFormat,
+    #[serde(default)]
+    pub output: OutputMode,
+    #[serde(default)]
+    pub manifest_csv: bool,
+    #[serde(default)]
+    pub format: ArchiveFormat,
+    // Compression level of the archive, its range depends on the format
+    pub level: Option<i32>,
+    // Zip without compressing any entry
+    #[serde(default)]
+    pub store: bool,
+    // Splits the archive into parts of at most this many bytes
+    pub max_part_size: Option<u64>,
+    // `keep` every copy or `store_once` with the other paths listed in duplicates.txt
+    #[serde(default)]
+    pub duplicates: DuplicatePolicy,
+    // `sidecar` writes a .meta.json next to every file, `jsonl` a single metadata.jsonl
+    #[serde(default)]
+    pub metadata: MetadataMode,
+    // Comma separated glob patterns on the path or the name of files, eg. `*.pdf,reports/*`
+    pub include: Option<String>,
+    pub exclude: Option<String>,
+    // Comma separated MIME types, globs such as `image/*` work as well
+    pub mime_types: Option<String>,
+    pub exclude_mime_types: Option<String>,
+    pub min_size: Option<i64>,
+    pub max_size: Option<i64>,
+    // RFC 3339 times, eg. `2024-01-31T00:00:00Z`
+    pub modified_after: Option<String>,
+    pub modified_before: Option<String>,
+    pub owner: Option<String>,
+    pub max_depth: Option<usize>,
+    // Revision of the linked file, listed by `/revisions/{id}`
+    pub revision: Option<String>,
+    // RFC 3339, only files modified after it are downloaded
+    pub modified_since: Option<String>,
+    // `follow`, `skip` or `link` for shortcuts inside folders
+    #[serde(default)]
+    pub shortcuts: ShortcutPolicy,
+    // Only report what the download would produce
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub order: MergeOrder,
+    #[serde(default)]
+    pub table_of_contents: bool,
+    // Comma separated export formats overriding the server defaults, eg. `docx,pdf`
+    pub document_format: Option<String>,
+    pub spreadsheet_format: Option<String>,
+    pub presentation_format: Option<String>,
+    pub drawing_format: Option<String>,
+}
+
+impl DownloadQuery {
+    pub fn get_options(
+        &self,
+        default_export_formats: &ExportFormats,
+        password: Option<Password>,
+    ) -> anyhow::Result<DownloadOptions> {
+        let mut export_formats = default_export_formats.clone();
+        for (query_formats, formats) in [
+            (&self.document_format, &mut export_formats.document),
+            (&self.spreadsheet_format, &mut export_formats.spreadsheet),
+            (&self.presentation_format, &mut export_formats.presentation),
+            (&self.drawing_format, &mut export_formats.drawing),
+        ] {
+            if let Some(query_formats) = query_formats {
+                *formats = ExportFormat::parse_list(query_formats.as_str())?;
+            }
+        }
+        export_formats.validate()?;
+
+        let archive = ArchiveOptions {
+            manifest_csv: self.manifest_csv,
+            format: self.format,
+            compression_level: self.level,
+            store_only: self.store,
+            password,
+            max_part_size: self.max_part_size,
+            duplicates: self.duplicates,
+            metadata: self.metadata,
+        };
+        archive.validate()?;
+
+        let parse_patterns = |patterns: &Option<String>| match patterns {
+            Some(patterns) => DownloadFilters::parse_patterns(patterns.as_str()),
+            None => Ok(Vec::new()),
+        };
+        let parse_time = |time: &Option<String>| match time {
+            Some(time) => DownloadFilters::parse_time(time.as_str()).map(Some),
+            None => Ok(None),
+        };
+        let filters = DownloadFilters {
+            include: parse_patterns(&self.include)?,
+            exclude: parse_patterns(&self.exclude)?,
+            mime_types: parse_patterns(&self.mime_types)?,
+            exclude_mime_types: parse_patterns(&self.exclude_mime_types)?,
+            min_size: self.min_size,
+            max_size: self.max_size,
+            modified_after: parse_time(&self.modified_after)?,
+            modified_before: parse_time(&self.modified_before)?,
+            owner: self.owner.clone(),
+            max_depth: self.max_depth,
+        };
+
+        Ok(DownloadOptions {
+            processing: ProcessingOptions {
+                compress_office_media: self.compress_office_media,
+                placeholder: self.placeholder,
+            },
+            export_formats,
+            output: self.output,
+            archive,
+            merge: MergeOptions {
+                order: self.order,
+                table_of_contents: self.table_of_contents,
+            },
+            filters,
+            revision: self.revision.clone(),
+            incremental: IncrementalOptions::new(parse_time(&self.modified_since)?, None),
+            shortcuts: self.shortcuts,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DownloadManyBody {
+    pub links: Vec<DownloadLink>,
+    // `manifest.json` of an earlier archive, only files which changed since then get downloaded
+    // Files of the manifest which are gone now are listed in `deletions.json`
+    pub manifest: Option<Vec<ManifestEntry>>,
+}
+
+// Sent as a header so that it never shows up in URLs or access logs
+pub fn get_password(req: &HttpRequest) -> Option<Password> {
+    req.headers()
+        .get("password")
+        .and_then(|password| password.to_str().ok())
+        .map(|password| Password::new(password.to_string()))
+}
+
+// Whether the output can be answered with a 304 before it is downloaded
+// Encrypted archives differ on every request and must not be revalidated against a password less one
+fn can_revalidate(req: &HttpRequest, options: &DownloadOptions) -> bool {
+    !options.is_encrypted() && req.headers().contains_key(IF_NONE_MATCH)
+}
+
+// Previews the links for dry runs, refuses downloads above the limits and answers
+// revalidations whose output did not change without downloading anything
+// Big downloads are walked upfront, the listings end up cached for the download itself
+pub async fn check_download(
+    drive_manager: &DriveManager,
+    links: &[&str],
+    options: &DownloadOptions,
+    dry_run: bool,
+    revalidation: Option<&HttpRequest>,
+) -> Option<HttpResponse> {
+    if !dry_run && !drive_manager.limits.is_enabled() && revalidation.is_none() {
+        return None;
+    }
+
+    let mut previews = Vec::new();
+    for link in links {
+        match drive_manager.preview(link, options.clone()).await {
+            Ok(preview) => previews.push(preview),
+            // Bundled links which fail are reported in the output instead of failing the download
+            Err(_) if links.len() > 1 => continue,
+            Err(e) => {
+                return Some(
+                    HttpResponse::InternalServerError()
+                        .json(GenericResponse::<()>::error(e.to_string().as_str())),
+                )
+            }
+        }
+    }
+
+    if dry_run {
+        return Some(match previews.len() {
+            1 => HttpResponse::Ok().json(GenericResponse::ok("Download preview", previews.pop())),
+            _ => HttpResponse::Ok().json(GenericResponse::ok("Download preview", Some(previews))),
+        });
+    }
+    if let Err(e) = drive_manager.limits.check(&previews) {
+        return Some(
+            HttpResponse::PayloadTooLarge()
+                .json(GenericResponse::<()>::error(e.to_string().as_str())),
+        );
+    }
+    if let Some(req) = revalidation {
+        let etag = Preview::get_etag(&previews, options);
+        if matches_etag(req, IF_NONE_MATCH, etag.as_str()) {
+            return Some(
+                HttpResponse::NotModified()
+                    .insert_header((ETAG, etag))
+                    .finish(),
+            );
+        }
+    }
+    None
+}
+
+pub fn serve_output(req: &HttpRequest, session: &DownloadSession) -> HttpResponse {
+    let options = &session.options;
+
+    // Outputs which cannot be revalidated before downloading them still save the transfer
+    let etag = session.get_etag();
+    if !options.is_encrypted() && matches_etag(req, IF_NONE_MATCH, etag.as_str()) {
+        session.remove_output();
+        return HttpResponse::NotModified()
+            .insert_header((ETAG, etag))
+            .finish();
+    }
+
+    let file = afs::NamedFile::open(session.output_path.as_str());
+    // The open handle keeps serving the content after the output is removed from disk
+    session.remove_output();
+    let file = match file {
+        Ok(file) => file,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .json(GenericResponse::<()>::error(e.to_string().as_str()))
+        }
+    };
+
+    // The output is already compressed so the Compress middleware has to leave it alone
+    let mut response = file
+        .use_etag(false)
+        .set_content_type(options.get_output_mime_type().parse().unwrap())
+        .set_content_encoding(ContentEncoding::Identity)
+        .set_content_disposition(get_content_disposition(
+            session.get_output_name().as_str(),
+            DispositionType::Attachment,
+        ))
+        .into_response(req);
+    if !options.is_encrypted() {
+        response
+            .headers_mut()
+            .insert(ETAG, HeaderValue::from_str(etag.as_str()).unwrap());
+    }
+    response
+}
 
 #[get("/download")]
-pub async fn download(req: HttpRequest, drive_manager: Data<DriveManager>) -> Result<HttpResponse> {
+pub async fn download(
+    req: HttpRequest,
+    drive_manager: Data<DriveManager>,
+    query: Query<DownloadQuery>,
+) -> Result<HttpResponse> {
     let start_time = Utc::now().time();
-    let link = req.headers().get("link");
+    let link = match req.headers().get("link").map(|link| link.to_str()) {
+        Some(Ok(link)) => link,
+        _ => {
+            return Ok(
+                HttpResponse::BadRequest().json(GenericResponse::<()>::error(
+                    "Missing or invalid `link` header",
+                )),
+            )
+        }
+    };
+
+    let options = match query.get_options(&drive_manager.export_formats, get_password(&req)) {
+        Ok(options) => options,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest()
+                .json(GenericResponse::<()>::error(e.to_string().as_str())))
+        }
+    };
+
+    // The previews of a single link have the same paths as its output
+    let revalidation = Some(&req).filter(|req| can_revalidate(req, &options));
+    if let Some(response) = check_download(
+        &drive_manager,
+        &[link],
+        &options,
+        query.dry_run,
+        revalidation,
+    )
+    .await
+    {
+        return Ok(response);
+    }
+
+    let session = match drive_manager.download_file(link, options).await {
+        Ok(session) => session,
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError()
+                .json(GenericResponse::<()>::error(e.to_string().as_str())))
+        }
+    };
+
+    let end_time = Utc::now().time();
+    let diff = end_time - start_time;
+
+    println!(
+        "--FINISHED_DOWNLOAD-- in {:?} secs",
+        diff.num_milliseconds()
+    );
+
+    Ok(serve_output(&req, &session))
+}
+
+// Bundles several links into a single output
+#[post("/download")]
+pub async fn download_many(
+    req: HttpRequest,
+    drive_manager: Data<DriveManager>,
+    query: Query<DownloadQuery>,
+    body: Json<DownloadManyBody>,
+) -> Result<HttpResponse> {
+    let start_time = Utc::now().time();
+    let DownloadManyBody { links, manifest } = body.into_inner();
+    if links.is_empty() {
+        return Ok(
+            HttpResponse::BadRequest().json(GenericResponse::<()>::error("No links to download"))
+        );
+    }
+
+    let mut options = match query.get_options(&drive_manager.export_formats, get_password(&req)) {
+        Ok(options) => options,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest()
+                .json(GenericResponse::<()>::error(e.to_string().as_str())))
+        }
+    };
+    options.incremental = IncrementalOptions::new(options.incremental.modified_since, manifest);
 
-    if link.is_none() {
-        return Ok(HttpResponse::new(StatusCode::BAD_REQUEST));
+    let link_urls = links
+        .iter()
+        .map(|link| link.link.as_str())
+        .collect::<Vec<_>>();
+    // Bundled links are renamed when merged so their previews cannot tell the ETag upfront
+    if let Some(response) =
+        check_download(&drive_manager, &link_urls, &options, query.dry_run, None).await
+    {
+        return Ok(response);
     }
 
-    drive_manager
-        .download_file(link.unwrap().to_str().unwrap())
-        .await
-        .unwrap();
+    let session = match drive_manager.download_files(links, options).await {
+        Ok(session) => session,
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError()
+                .json(GenericResponse::<()>::error(e.to_string().as_str())))
+        }
+    };
 
     let end_time = Utc::now().time();
     let diff = end_time - start_time;
@@ -31,10 +384,5 @@ pub async fn download(req: HttpRequest, drive_manager: Data<DriveManager>) -> Re
         diff.num_milliseconds()
     );
 
-    let file = afs::NamedFile::open("tmp/output.zip").unwrap();
-    Ok(file
-        .set_content_type(file_extension_to_mime("zip"))
-        .set_content_encoding(ContentEncoding::Gzip)
-        .set_content_disposition(ContentDisposition::attachment("output.zip"))
-        .into_response(&req))
+    Ok(serve_output(&req, &session))
 }
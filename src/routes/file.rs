@@ -0,0 +1,198 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_files as afs;
+use actix_web::{
+    get,
+    http::header::{
+        CacheControl, CacheDirective, ContentEncoding, DispositionType, Header, HeaderValue,
+        HttpDate, IfModifiedSince, CACHE_CONTROL, ETAG, IF_NONE_MATCH, LAST_MODIFIED,
+    },
+    web::{Data, Path, Query},
+    HttpRequest, HttpResponse, Result,
+};
+use drive::chrono::{DateTime, Utc};
+use drive_manager::{interface::DownloadOptions, DriveManager};
+use fs::interface::{ExportFormat, PlaceholderFormat, ProcessingOptions};
+use serde::Deserialize;
+
+use super::interface::{get_content_disposition, matches_etag, GenericResponse};
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Disposition {
+    // Lets browsers preview PDFs and play videos in place
+    #[default]
+    Inline,
+    Attachment,
+}
+
+#[derive(Deserialize)]
+pub struct FileQuery {
+    #[serde(default)]
+    pub disposition: Disposition,
+    // Export format of Workspace files, falls back to the server default of the type
+    pub format: Option<String>,
+    #[serde(default)]
+    pub compress_office_media: bool,
+    #[serde(default)]
+    pub placeholder: PlaceholderFormat,
+    // Revision to serve instead of the head one
+    pub revision: Option<String>,
+}
+
+impl FileQuery {
+    pub fn get_options(&self, drive_manager: &DriveManager) -> anyhow::Result<DownloadOptions> {
+        let format = match self.format.as_ref() {
+            Some(format) => Some(format.parse::<ExportFormat>()?),
+            None => None,
+        };
+
+        Ok(DownloadOptions {
+            processing: ProcessingOptions {
+                compress_office_media: self.compress_office_media,
+                placeholder: self.placeholder,
+            },
+            export_formats: drive_manager.export_formats.with_single_format(format),
+            revision: self.revision.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+// If-Modified-Since is only looked at without an If-None-Match, HTTP dates have whole seconds
+fn is_unmodified_since(req: &HttpRequest, modified_time: Option<DateTime<Utc>>) -> bool {
+    let (Some(modified_time), std::result::Result::Ok(IfModifiedSince(since))) =
+        (modified_time, IfModifiedSince::parse(req))
+    else {
+        return false;
+    };
+    match SystemTime::from(since).duration_since(UNIX_EPOCH) {
+        Ok(since) => modified_time.timestamp() <= since.as_secs() as i64,
+        Err(_) => false,
+    }
+}
+
+// Serves a single file as is so that Range requests and previews work
+#[get("/file/{id}")]
+pub async fn file(
+    req: HttpRequest,
+    drive_manager: Data<DriveManager>,
+    id: Path<String>,
+    query: Query<FileQuery>,
+) -> Result<HttpResponse> {
+    let options = match query.get_options(&drive_manager) {
+        Ok(options) => options,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest()
+                .json(GenericResponse::<()>::error(e.to_string().as_str())))
+        }
+    };
+
+    // Revalidations are answered from the metadata alone, without downloading anything
+    let file_manager = match drive_manager
+        .describe_single_file(id.as_str(), &options)
+        .await
+    {
+        Ok(file_manager) => file_manager,
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError()
+                .json(GenericResponse::<()>::error(e.to_string().as_str())))
+        }
+    };
+
+    // Workspace files have no revision, their version changes with every edit instead
+    // The cache key tells the export formats and processed variants apart
+    let etag = format!(
+        "\"{}/{}\"",
+        file_manager.get_content_version(),
+        file_manager.get_cache_key()
+    );
+    // The cached copy only knows when it was downloaded, Drive knows when the file changed
+    let last_modified = file_manager
+        .file
+        .modified_time
+        .map(|modified_time| HttpDate::from(SystemTime::from(modified_time)).to_string());
+    // A revision never changes once created, so clients can keep it for good
+    let cache_control = query.revision.as_ref().map(|_| {
+        CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(31536000),
+            CacheDirective::Extension("immutable".to_string(), None),
+        ])
+    });
+    let not_modified = if req.headers().contains_key(IF_NONE_MATCH) {
+        matches_etag(&req, IF_NONE_MATCH, etag.as_str())
+    } else {
+        is_unmodified_since(&req, file_manager.file.modified_time)
+    };
+    if not_modified {
+        let mut response = HttpResponse::NotModified();
+        response.insert_header((ETAG, etag));
+        if let Some(last_modified) = last_modified {
+            response.insert_header((LAST_MODIFIED, last_modified));
+        }
+        if let Some(cache_control) = cache_control {
+            response.insert_header(cache_control);
+        }
+        return Ok(response.finish());
+    }
+
+    let single_file = match drive_manager
+        .download_single_file(id.as_str(), options)
+        .await
+    {
+        Ok(single_file) => single_file,
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError()
+                .json(GenericResponse::<()>::error(e.to_string().as_str())))
+        }
+    };
+
+    let file = match afs::NamedFile::open(single_file.path.as_str()) {
+        Ok(file) => file,
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError()
+                .json(GenericResponse::<()>::error(e.to_string().as_str())))
+        }
+    };
+
+    let disposition = match query.disposition {
+        Disposition::Inline => DispositionType::Inline,
+        Disposition::Attachment => DispositionType::Attachment,
+    };
+    // Partial responses must not be compressed by the middleware
+    let mut response = file
+        .use_etag(false)
+        .use_last_modified(false)
+        .set_content_type(
+            single_file
+                .file_manager
+                .mime_type
+                .parse()
+                .unwrap_or_else(|_| {
+                    afs::file_extension_to_mime(single_file.file_manager.ext.as_str())
+                }),
+        )
+        .set_content_encoding(ContentEncoding::Identity)
+        .set_content_disposition(get_content_disposition(
+            single_file.file_manager.file_name.as_str(),
+            disposition,
+        ))
+        .into_response(&req);
+    response
+        .headers_mut()
+        .insert(ETAG, HeaderValue::from_str(etag.as_str()).unwrap());
+    if let Some(last_modified) = last_modified {
+        response.headers_mut().insert(
+            LAST_MODIFIED,
+            HeaderValue::from_str(&last_modified).unwrap(),
+        );
+    }
+    if let Some(cache_control) = cache_control {
+        response.headers_mut().insert(
+            CACHE_CONTROL,
+            HeaderValue::from_str(&cache_control.to_string()).unwrap(),
+        );
+    }
+    Ok(response)
+}
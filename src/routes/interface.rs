@@ -1,6 +1,12 @@
 use std::any::Any;
 
-use actix_web::web::Json;
+use actix_web::{
+    http::header::{
+        Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue, HeaderName,
+    },
+    web::Json,
+    HttpRequest,
+};
 use serde::Serialize;
 
 #[derive(Serialize)]
@@ -15,7 +21,7 @@ impl<T: Any> GenericResponse<T> {
     pub fn ok(message: &str, details: Option<T>) -> Json<Self> {
         Json(Self {
             success: true,
-            details: details,
+            details,
             message: Some(message.to_string()),
             error: None,
         })
@@ -30,3 +36,50 @@ impl<T: Any> GenericResponse<T> {
         })
     }
 }
+
+// Content-Disposition with an ASCII fallback and the RFC 5987 encoded name for everything else
+pub fn get_content_disposition(name: &str, disposition: DispositionType) -> ContentDisposition {
+    // Separators, quotes and control characters would break either the header or the saved path
+    let name = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| {
+            if matches!(c, '/' | '\\' | '"') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect::<String>();
+    let ascii_name = name
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect::<String>();
+
+    let mut parameters = vec![DispositionParam::Filename(ascii_name)];
+    if !name.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_string()),
+            language_tag: None,
+            value: name.into_bytes(),
+        }));
+    }
+
+    ContentDisposition {
+        disposition,
+        parameters,
+    }
+}
+
+// Whether a conditional header such as If-None-Match lists the ETag
+pub fn matches_etag(req: &HttpRequest, header: HeaderName, etag: &str) -> bool {
+    req.headers()
+        .get(header)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(',')
+                .any(|tag| tag.trim() == "*" || tag.trim() == etag)
+        })
+        .unwrap_or(false)
+}
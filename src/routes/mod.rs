@@ -1,4 +1,9 @@
+pub mod check;
 pub mod download;
+pub mod file;
 pub mod interface;
+pub mod revisions;
+pub mod roster;
 pub mod shortcut;
+pub mod stream;
 pub mod upload;
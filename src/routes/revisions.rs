@@ -0,0 +1,23 @@
+use actix_web::{
+    get,
+    web::{Data, Path},
+    HttpResponse, Result,
+};
+use drive_manager::DriveManager;
+
+use super::interface::GenericResponse;
+
+// Revisions which can be passed as `revision` to `/download` and `/file/{id}`
+#[get("/revisions/{id}")]
+pub async fn revisions(
+    drive_manager: Data<DriveManager>,
+    id: Path<String>,
+) -> Result<HttpResponse> {
+    match drive_manager.list_revisions(id.as_str()).await {
+        Ok(revisions) => {
+            Ok(HttpResponse::Ok().json(GenericResponse::ok("Revisions", Some(revisions))))
+        }
+        Err(e) => Ok(HttpResponse::InternalServerError()
+            .json(GenericResponse::<()>::error(e.to_string().as_str()))),
+    }
+}
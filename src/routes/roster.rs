@@ -0,0 +1,107 @@
+use actix_web::{
+    post,
+    web::{Bytes, Data, Query},
+    HttpRequest, HttpResponse, Result,
+};
+use drive::chrono::Utc;
+use drive_manager::{
+    interface::NamingTemplate,
+    link::Link,
+    roster::{parse_csv, parse_json},
+    DriveManager,
+};
+use serde::Deserialize;
+
+use super::{
+    download::{check_download, get_password, serve_output, DownloadQuery},
+    interface::GenericResponse,
+};
+
+#[derive(Deserialize)]
+pub struct RosterQuery {
+    // Name of every entry, eg. `{roll}_{name}.{ext}` where `roll` is a column of the roster
+    #[serde(default = "RosterQuery::default_template")]
+    pub template: String,
+}
+
+impl RosterQuery {
+    fn default_template() -> String {
+        "{row}_{name}.{ext}".to_string()
+    }
+}
+
+// Downloads the link of every row of a CSV or JSON roster into a single output
+// Rows whose links are private, broken or not Drive links are listed in `errors.json`
+#[post("/roster")]
+pub async fn roster(
+    req: HttpRequest,
+    drive_manager: Data<DriveManager>,
+    query: Query<DownloadQuery>,
+    roster_query: Query<RosterQuery>,
+    body: Bytes,
+) -> Result<HttpResponse> {
+    let start_time = Utc::now().time();
+    let is_json = req
+        .headers()
+        .get("content-type")
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.contains("json"));
+    let parsed_roster = match is_json {
+        true => parse_json(&body),
+        false => parse_csv(&body),
+    };
+    let (columns, rows) = match parsed_roster {
+        Ok(parsed_roster) => parsed_roster,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest()
+                .json(GenericResponse::<()>::error(e.to_string().as_str())))
+        }
+    };
+    if rows.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(GenericResponse::<()>::error("Roster is empty")));
+    }
+
+    let template = match NamingTemplate::new(roster_query.template.clone(), &columns) {
+        Ok(template) => template,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest()
+                .json(GenericResponse::<()>::error(e.to_string().as_str())))
+        }
+    };
+    let options = match query.get_options(&drive_manager.export_formats, get_password(&req)) {
+        Ok(options) => options,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest()
+                .json(GenericResponse::<()>::error(e.to_string().as_str())))
+        }
+    };
+
+    let links = rows
+        .iter()
+        .filter(|row| Link::new(row.link.clone()).is_drive_link())
+        .map(|row| row.link.as_str())
+        .collect::<Vec<_>>();
+    if let Some(response) =
+        check_download(&drive_manager, &links, &options, query.dry_run, None).await
+    {
+        return Ok(response);
+    }
+
+    let session = match drive_manager.download_roster(rows, template, options).await {
+        Ok(session) => session,
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError()
+                .json(GenericResponse::<()>::error(e.to_string().as_str())))
+        }
+    };
+
+    let end_time = Utc::now().time();
+    let diff = end_time - start_time;
+
+    println!(
+        "--FINISHED_DOWNLOAD-- in {:?} secs",
+        diff.num_milliseconds()
+    );
+
+    Ok(serve_output(&req, &session))
+}
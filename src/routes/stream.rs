@@ -0,0 +1,76 @@
+use actix_files as afs;
+use actix_web::{
+    get,
+    http::{
+        header::{
+            ContentEncoding, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE,
+        },
+        StatusCode,
+    },
+    web::{Data, Path},
+    HttpRequest, HttpResponse, Result,
+};
+use drive_manager::{interface::StreamSource, DriveManager};
+
+use super::interface::GenericResponse;
+
+// Proxies large media from Drive, forwarding the Range of the client instead of downloading everything first
+#[get("/stream/{id}")]
+pub async fn stream(
+    req: HttpRequest,
+    drive_manager: Data<DriveManager>,
+    id: Path<String>,
+) -> Result<HttpResponse> {
+    let range = req
+        .headers()
+        .get(RANGE)
+        .and_then(|range| range.to_str().ok());
+
+    let source = match drive_manager.stream_file(id.as_str(), range).await {
+        Ok(source) => source,
+        Err(e) => {
+            return Ok(HttpResponse::BadGateway()
+                .json(GenericResponse::<()>::error(e.to_string().as_str())))
+        }
+    };
+
+    match source {
+        StreamSource::Cached(single_file) => {
+            let file = match afs::NamedFile::open(single_file.path.as_str()) {
+                Ok(file) => file,
+                Err(e) => {
+                    return Ok(HttpResponse::InternalServerError()
+                        .json(GenericResponse::<()>::error(e.to_string().as_str())))
+                }
+            };
+            Ok(file
+                .set_content_type(
+                    single_file
+                        .file_manager
+                        .mime_type
+                        .parse()
+                        .unwrap_or_else(|_| {
+                            afs::file_extension_to_mime(single_file.file_manager.ext.as_str())
+                        }),
+                )
+                .set_content_encoding(ContentEncoding::Identity)
+                .disable_content_disposition()
+                .into_response(&req))
+        }
+        StreamSource::Drive(drive_stream) => {
+            let mut response =
+                HttpResponse::build(StatusCode::from_u16(drive_stream.status).unwrap());
+            response
+                .insert_header((CONTENT_TYPE, drive_stream.mime_type))
+                .insert_header((ACCEPT_RANGES, "bytes"))
+                .insert_header(ContentEncoding::Identity);
+            if let Some(content_length) = drive_stream.content_length {
+                response.insert_header((CONTENT_LENGTH, content_length));
+            }
+            if let Some(content_range) = drive_stream.content_range {
+                response.insert_header((CONTENT_RANGE, content_range));
+            }
+            Ok(response.streaming(drive_stream.body))
+        }
+    }
+}
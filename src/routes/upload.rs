@@ -4,7 +4,11 @@ use actix_web::{
     web::{Data, Json},
     Responder, Result,
 };
-use drive_manager::{interface::CreateFileStruct, link::Link, DriveManager};
+use drive_manager::{
+    interface::{CreateFileStruct, UploadError},
+    link::Link,
+    DriveManager,
+};
 use serde::Serialize;
 
 #[derive(Debug, MultipartForm)]
@@ -21,6 +25,9 @@ struct UploadForm {
 struct UploadResponse {
     message: &'static str,
     urls: Vec<Option<String>>,
+    // Files which could not be uploaded, their links are missing from `urls`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<UploadError>,
 }
 
 #[post("/upload")]
@@ -46,7 +53,7 @@ pub async fn upload(
             None
         };
         let file_id = if form.links.get(idx).is_some() {
-            Some(Link::new(form.links.get(idx).clone().unwrap().to_string()).id)
+            Some(Link::new(form.links.get(idx).unwrap().to_string()).id)
         } else {
             None
         };
@@ -59,13 +66,21 @@ pub async fn upload(
             file_id,
             parents: parents.clone(),
             content,
+            // Large files go up in chunks so a network error does not restart them from zero
+            resumable: file.size as u64 >= drive_manager.uploads.resumable_threshold,
         });
     }
 
-    let urls = drive_manager.upload_files(file_paths).await.unwrap();
+    let upload_batch = drive_manager.upload_files(file_paths).await.unwrap();
+    let message = if upload_batch.errors.is_empty() {
+        "Data Uploaded Successfully"
+    } else {
+        "Some files could not be uploaded"
+    };
 
     Ok(Json(UploadResponse {
-        message: "Data Uploaded Successfully",
-        urls,
+        message,
+        urls: upload_batch.urls,
+        errors: upload_batch.errors,
     }))
 }